@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{Authenticator, Streamable, ToBytes};
+
+const DEFAULT_MAX_SKEW: Duration = Duration::from_secs(60);
+
+// ===== Hello =====
+#[rustfmt::skip]
+mod consts_hello {
+    pub const MARKER:             u8 = 0xF0;
+    pub const VERSION_AUTH:       u8 = 0x01;
+    pub const VERSION_EXTENSIONS: u8 = 0x02;
+    pub const VERSION:            u8 = 0x03;
+
+    pub const VERSIONS:           [u8; 3] = [VERSION_AUTH, VERSION_EXTENSIONS, VERSION];
+
+    pub const NONCE_SIZE:         usize = 16;
+    pub const MAC_SIZE:           usize = 32;
+}
+
+/// A user id and the pre-shared key a `Client` authenticates with.
+#[derive(Clone)]
+pub struct Credentials {
+    user: String,
+    key: Vec<u8>,
+}
+
+impl Credentials {
+    pub fn new<U, K>(user: U, key: K) -> Self
+    where
+        U: Into<String>,
+        K: Into<Vec<u8>>,
+    {
+        Self {
+            user: user.into(),
+            key: key.into(),
+        }
+    }
+}
+
+/// Sent by a `Client` ahead of its [`Request`], announcing its version and
+/// authenticating it to servers that require it. Servers that do not
+/// authenticate skip the credentials. The `MARKER` byte never starts a
+/// `Request`.
+///
+/// ## Bytes
+/// ```text
+///          +--------+-----+------+----------+------+-------+-----+
+///          | MARKER | VER | ULEN |   USER   | TIME | NONCE | MAC |
+///          +--------+-----+------+----------+------+-------+-----+
+///          |   1    |  1  |  1   | Variable |  8   |  16   | 32  |
+///          +--------+-----+------+----------+------+-------+-----+
+/// ```
+///
+/// `VER` is `0x03`, the server answers with a [`ServerHello`] listing the
+/// versions it supports, and closes the stream after it if `VER` is not one
+/// of them. Hellos of versions `0x01` and `0x02`, the latter ahead of a
+/// request with [`Extensions`], are still accepted and get no answer. A hello
+/// of version `0x03` without credentials ends after `ULEN`, which is `0`.
+/// `TIME` is in seconds since the Unix epoch and
+/// `MAC` is HMAC-SHA256 with the pre-shared key over
+/// `VER | ULEN | USER | TIME | NONCE`.
+///
+/// [`Request`]: crate::request::Request
+/// [`Extensions`]: crate::request::Extensions
+#[derive(Debug, Clone)]
+pub struct Hello {
+    pub version: u8,
+    pub user: String,
+    pub timestamp: u64,
+    pub nonce: [u8; consts_hello::NONCE_SIZE],
+    pub mac: [u8; consts_hello::MAC_SIZE],
+}
+
+impl Hello {
+    /// A hello for the current time with a random nonce.
+    pub fn new(credentials: &Credentials) -> Result<Self> {
+        let mut nonce = [0u8; consts_hello::NONCE_SIZE];
+        getrandom::fill(&mut nonce).map_err(Error::from)?;
+
+        let mut hello = Self {
+            version: consts_hello::VERSION,
+            user: credentials.user.clone(),
+            timestamp: unix_time(),
+            nonce,
+            mac: [0u8; consts_hello::MAC_SIZE],
+        };
+        hello.mac = hello.mac(&credentials.key).finalize().into_bytes().into();
+
+        Ok(hello)
+    }
+
+    /// A hello only announcing the version, of a client without credentials.
+    pub fn anonymous() -> Self {
+        Self {
+            version: consts_hello::VERSION,
+            user: String::new(),
+            timestamp: 0,
+            nonce: [0u8; consts_hello::NONCE_SIZE],
+            mac: [0u8; consts_hello::MAC_SIZE],
+        }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.user.is_empty()
+    }
+
+    /// Whether the sender reads a [`ServerHello`] before the response.
+    pub fn expects_answer(&self) -> bool {
+        self.version >= consts_hello::VERSION
+    }
+
+    /// Checks the MAC against `key` in constant time.
+    pub fn verify(&self, key: &[u8]) -> bool {
+        self.mac(key).verify_slice(&self.mac).is_ok()
+    }
+
+    fn mac(&self, key: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+
+        mac.update(&[self.version, self.user.len() as u8]);
+        mac.update(self.user.as_bytes());
+        mac.update(&self.timestamp.to_be_bytes());
+        mac.update(&self.nonce);
+
+        mac
+    }
+}
+
+impl ToBytes for Hello {
+    fn to_bytes(self) -> Bytes {
+        let mut bytes = BytesMut::new();
+
+        bytes.put_u8(consts_hello::MARKER);
+        bytes.put_u8(self.version);
+        bytes.put_u8(self.user.len() as u8);
+
+        if self.expects_answer() && self.is_anonymous() {
+            return bytes.freeze();
+        }
+
+        bytes.extend_from_slice(self.user.as_bytes());
+        bytes.put_u64(self.timestamp);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.mac);
+
+        bytes.freeze()
+    }
+}
+
+impl Streamable for Hello {
+    async fn write<T>(self, stream: &mut T) -> Result<()>
+    where
+        T: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        if self.user.len() > u8::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "user id too long"));
+        }
+
+        stream.write_all(&self.to_bytes()).await
+    }
+
+    async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        let marker = stream.read_u8().await?;
+        if !Self::is_marker(marker) {
+            return Err(Error::new(ErrorKind::InvalidData, "expected hello"));
+        }
+
+        Self::read_after_marker(stream).await
+    }
+}
+
+impl Hello {
+    pub(crate) fn is_marker(byte: u8) -> bool {
+        byte == consts_hello::MARKER
+    }
+
+    pub(crate) async fn read_after_marker<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        let version = stream.read_u8().await?;
+        if !consts_hello::VERSIONS.contains(&version) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported hello version {}", version),
+            ));
+        }
+
+        let user_len = stream.read_u8().await? as usize;
+        if user_len == 0 && version >= consts_hello::VERSION {
+            return Ok(Self::anonymous());
+        }
+
+        let mut user = vec![0u8; user_len];
+        stream.read_exact(&mut user).await?;
+        let user = String::from_utf8(user).map_err(|_| Error::other("invalid user id"))?;
+
+        let timestamp = stream.read_u64().await?;
+
+        let mut nonce = [0u8; consts_hello::NONCE_SIZE];
+        stream.read_exact(&mut nonce).await?;
+
+        let mut mac = [0u8; consts_hello::MAC_SIZE];
+        stream.read_exact(&mut mac).await?;
+
+        Ok(Self {
+            version,
+            user,
+            timestamp,
+            nonce,
+            mac,
+        })
+    }
+}
+
+// ===== ServerHello =====
+/// Answers a [`Hello`] of version `0x03` or of a version the server does not
+/// support, ahead of the `Response`. The `MARKER` byte never starts a
+/// `Response`.
+///
+/// ## Bytes
+/// ```text
+///          +--------+------+----------+
+///          | MARKER | VLEN | VERSIONS |
+///          +--------+------+----------+
+///          |   1    |  1   | Variable |
+///          +--------+------+----------+
+/// ```
+///
+/// `VERSIONS` are the hello versions the server supports, one byte each.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerHello {
+    pub versions: Vec<u8>,
+}
+
+impl Default for ServerHello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerHello {
+    /// The answer of this server.
+    pub fn new() -> Self {
+        Self {
+            versions: consts_hello::VERSIONS.to_vec(),
+        }
+    }
+
+    /// Fails with `Unsupported` unless the server supports the version of
+    /// the hellos sent by this client.
+    pub fn check(&self) -> Result<()> {
+        if self.versions.contains(&consts_hello::VERSION) {
+            return Ok(());
+        }
+
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "server supports hello versions {:?}, not {}",
+                self.versions,
+                consts_hello::VERSION
+            ),
+        ))
+    }
+}
+
+impl ToBytes for ServerHello {
+    fn to_bytes(self) -> Bytes {
+        let mut bytes = BytesMut::new();
+
+        bytes.put_u8(consts_hello::MARKER);
+        bytes.put_u8(self.versions.len() as u8);
+        bytes.extend_from_slice(&self.versions);
+
+        bytes.freeze()
+    }
+}
+
+impl Streamable for ServerHello {
+    async fn write<T>(self, stream: &mut T) -> Result<()>
+    where
+        T: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        stream.write_all(&self.to_bytes()).await
+    }
+
+    async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        let marker = stream.read_u8().await?;
+        if marker != consts_hello::MARKER {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "server does not answer hellos",
+            ));
+        }
+
+        let versions_len = stream.read_u8().await? as usize;
+        let mut versions = vec![0u8; versions_len];
+        stream.read_exact(&mut versions).await?;
+
+        Ok(Self { versions })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+// ===== PskAuthenticator =====
+/// Accepts hellos of known users carrying a valid MAC with a timestamp within
+/// the allowed clock skew. Nonces are remembered for as long as their
+/// timestamp is accepted, so a captured hello can not be replayed.
+pub struct PskAuthenticator {
+    users: HashMap<String, Vec<u8>>,
+    max_skew: Duration,
+    seen: Mutex<HashMap<[u8; consts_hello::NONCE_SIZE], u64>>,
+}
+
+impl Default for PskAuthenticator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PskAuthenticator {
+    pub fn new() -> Self {
+        Self {
+            users: HashMap::new(),
+            max_skew: DEFAULT_MAX_SKEW,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a user and its pre-shared key, may be called repeatedly.
+    pub fn with_user<U, K>(mut self, user: U, key: K) -> Self
+    where
+        U: Into<String>,
+        K: Into<Vec<u8>>,
+    {
+        self.users.insert(user.into(), key.into());
+        self
+    }
+
+    /// How far a hello's timestamp may be off the local clock.
+    pub fn with_max_skew(mut self, max_skew: Duration) -> Self {
+        self.max_skew = max_skew;
+        self
+    }
+}
+
+impl Authenticator for PskAuthenticator {
+    fn authenticate(&self, hello: &Hello) -> Result<()> {
+        let denied = |reason: &str| Error::new(ErrorKind::PermissionDenied, reason.to_string());
+
+        if hello.is_anonymous() {
+            return Err(denied("missing credentials"));
+        }
+
+        let key = self
+            .users
+            .get(&hello.user)
+            .ok_or_else(|| denied("unknown user"))?;
+
+        let now = unix_time();
+        if now.abs_diff(hello.timestamp) > self.max_skew.as_secs() {
+            return Err(denied("hello timestamp out of range"));
+        }
+
+        if !hello.verify(key) {
+            return Err(denied("invalid hello mac"));
+        }
+
+        let mut seen = self
+            .seen
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        seen.retain(|_, timestamp| now.abs_diff(*timestamp) <= self.max_skew.as_secs());
+
+        if seen.insert(hello.nonce, hello.timestamp).is_some() {
+            return Err(denied("replayed hello"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    /// HMAC-SHA256 of the hello below, as computed by version `0x01` clients.
+    #[rustfmt::skip]
+    const MAC: [u8; consts_hello::MAC_SIZE] = [
+        0x1c, 0x3b, 0x46, 0x45, 0xbd, 0xaf, 0xcb, 0x53,
+        0x03, 0x95, 0x5f, 0x5c, 0x95, 0x72, 0x6f, 0x50,
+        0x7b, 0xc5, 0xc2, 0xaa, 0x15, 0xa7, 0xcc, 0x69,
+        0x35, 0x1e, 0x3e, 0xd7, 0x32, 0x13, 0xdf, 0xe9,
+    ];
+
+    fn hello(version: u8) -> Hello {
+        let mut nonce = [0u8; consts_hello::NONCE_SIZE];
+        nonce.iter_mut().zip(0..).for_each(|(byte, i)| *byte = i);
+
+        let mut hello = Hello {
+            version,
+            user: "alice".to_string(),
+            timestamp: 1_700_000_000,
+            nonce,
+            mac: [0u8; consts_hello::MAC_SIZE],
+        };
+        hello.mac = hello.mac(KEY).finalize().into_bytes().into();
+        hello
+    }
+
+    #[test]
+    fn hello_of_the_first_version_keeps_its_encoding() {
+        let hello = hello(consts_hello::VERSION_AUTH);
+        assert_eq!(hello.mac, MAC);
+
+        let mut expected = vec![0xF0, 0x01, 0x05];
+        expected.extend_from_slice(b"alice");
+        expected.extend_from_slice(&1_700_000_000u64.to_be_bytes());
+        expected.extend(0..16);
+        expected.extend_from_slice(&MAC);
+
+        assert_eq!(hello.to_bytes()[..], expected[..]);
+    }
+
+    #[test]
+    fn hello_announces_the_current_version() {
+        let hello = Hello::new(&Credentials::new("alice", KEY)).unwrap();
+        assert_eq!(hello.version, consts_hello::VERSION);
+        assert!(hello.expects_answer());
+        assert!(hello.verify(KEY));
+        assert!(!hello.verify(b"other"));
+
+        let hello = Hello::anonymous();
+        assert!(hello.expects_answer());
+        assert_eq!(hello.to_bytes()[..], [0xF0, 0x03, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn hello_reads_back() {
+        for version in consts_hello::VERSIONS {
+            let mut stream = Cursor::new(hello(version).to_bytes().to_vec());
+            let read = Hello::read(&mut stream).await.unwrap();
+
+            assert_eq!(read.version, version);
+            assert_eq!(read.user, "alice");
+            assert!(read.verify(KEY));
+        }
+
+        let mut stream = Cursor::new(vec![0xF0, 0x03, 0x00, 0x01]);
+        let read = Hello::read(&mut stream).await.unwrap();
+        assert!(read.is_anonymous());
+        assert_eq!(stream.read_u8().await.unwrap(), 0x01);
+
+        for version in [0x00, 0x04] {
+            let mut bytes = hello(consts_hello::VERSION).to_bytes().to_vec();
+            bytes[1] = version;
+            let error = Hello::read(&mut Cursor::new(bytes)).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::Unsupported);
+        }
+    }
+
+    #[tokio::test]
+    async fn server_hello_reads_back() {
+        let bytes = ServerHello::new().to_bytes();
+        assert_eq!(bytes[..], [0xF0, 0x03, 0x01, 0x02, 0x03]);
+
+        let read = ServerHello::read(&mut Cursor::new(bytes.to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(read, ServerHello::new());
+        read.check().unwrap();
+
+        // A response in its place comes from a server predating the answer.
+        let error = ServerHello::read(&mut Cursor::new(vec![0x01, 0x00]))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn server_hello_without_the_current_version_is_rejected() {
+        let server_hello = ServerHello {
+            versions: vec![consts_hello::VERSION_AUTH, consts_hello::VERSION_EXTENSIONS],
+        };
+
+        let error = server_hello.check().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn authenticator_accepts_every_version_once() {
+        let authenticator = PskAuthenticator::new().with_user("alice", KEY);
+        let credentials = Credentials::new("alice", KEY);
+
+        for version in consts_hello::VERSIONS {
+            let mut hello = Hello::new(&credentials).unwrap();
+            hello.version = version;
+            hello.mac = hello.mac(KEY).finalize().into_bytes().into();
+            authenticator.authenticate(&hello).unwrap();
+
+            let error = authenticator.authenticate(&hello).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        }
+
+        let error = authenticator.authenticate(&Hello::anonymous()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+}
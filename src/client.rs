@@ -1,12 +1,138 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
+use bytes::Bytes;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
+use tokio::net::TcpStream;
 
-use crate::{request::Request, Provider};
+use crate::auth::Credentials;
+use crate::request::Extensions;
+use crate::{request::Request, response::Response, Provider, Reply};
+
+/// Most local bytes sent along with a request as early data.
+const MAX_EARLY_DATA: usize = 16 * 1024;
+
+pub struct ClientBuilder<L, R, LS, RS> {
+    local: Option<L>,
+    remote: Option<R>,
+    credentials: Option<Credentials>,
+    extensions: Extensions,
+    early_data: Option<Duration>,
+    optimistic: bool,
+    _local_stream: PhantomData<LS>,
+    _remote_stream: PhantomData<RS>,
+}
+
+impl<L, R, LS, RS> Default for ClientBuilder<L, R, LS, RS>
+where
+    L: Provider<(LS, Request)>,
+    R: Provider<RS>,
+    LS: AsyncReadExt + AsyncWriteExt + Reply + Unpin + Send + 'static,
+    RS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L, R, LS, RS> ClientBuilder<L, R, LS, RS>
+where
+    L: Provider<(LS, Request)>,
+    R: Provider<RS>,
+    LS: AsyncReadExt + AsyncWriteExt + Reply + Unpin + Send + 'static,
+    RS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            local: None,
+            remote: None,
+            credentials: None,
+            extensions: Extensions::new(),
+            early_data: None,
+            optimistic: false,
+            _local_stream: PhantomData,
+            _remote_stream: PhantomData,
+        }
+    }
+
+    pub fn with_local(mut self, local: L) -> Self {
+        self.local = Some(local);
+        self
+    }
+
+    pub fn with_remote(mut self, remote: R) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// Authenticates the [`Hello`] sent ahead of every request, which only
+    /// announces the client's version otherwise.
+    ///
+    /// [`Hello`]: crate::auth::Hello
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Extensions sent along with every request.
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Sends the first local bytes of a `TCPConnect` along with its request
+    /// instead of a round trip later. The local side is answered as with
+    /// [`with_optimistic_response`] and given up to `wait` to send them.
+    /// Protocols where the server speaks first are delayed by `wait`. Further
+    /// local bytes wait for the server's response, the early data is sent
+    /// again if the response does not acknowledge it, e.g. from servers
+    /// predating early data.
+    ///
+    /// [`with_optimistic_response`]: Self::with_optimistic_response
+    pub fn with_early_data(mut self, wait: Duration) -> Self {
+        self.early_data = Some(wait);
+        self
+    }
+
+    /// Answers the local side of a `TCPConnect` right away and relays its
+    /// bytes without waiting for the server's response, which is only read
+    /// once the remote side sends. A failure to reach the target then closes
+    /// the local side instead.
+    pub fn with_optimistic_response(mut self, optimistic: bool) -> Self {
+        self.optimistic = optimistic;
+        self
+    }
+
+    pub fn build(self) -> Client<L, R, LS, RS> {
+        let context = ClientHandlerContext {
+            credentials: self.credentials,
+            extensions: self.extensions,
+            early_data: self.early_data,
+            optimistic: self.optimistic,
+        };
+
+        Client {
+            local: self.local.expect("Local must be provided"),
+            remote: self.remote.expect("Remote must be provided"),
+            context: Arc::new(context),
+            _local_stream: PhantomData,
+            _remote_stream: PhantomData,
+        }
+    }
+}
+
+struct ClientHandlerContext {
+    credentials: Option<Credentials>,
+    extensions: Extensions,
+    early_data: Option<Duration>,
+    optimistic: bool,
+}
 
 pub struct Client<L, R, LS, RS> {
     local: L,
     remote: R,
+    context: Arc<ClientHandlerContext>,
     _local_stream: PhantomData<LS>,
     _remote_stream: PhantomData<RS>,
 }
@@ -15,31 +141,297 @@ impl<L, R, LS, RS> Client<L, R, LS, RS>
 where
     L: Provider<(LS, Request)>,
     R: Provider<RS>,
-    LS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+    LS: AsyncReadExt + AsyncWriteExt + Reply + Unpin + Send + 'static,
     RS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
 {
     pub async fn start(&mut self) {
-        while let Some((local, request)) = self.local.fetch().await {
-            if let Some(remote) = self.remote.fetch().await {
-                tokio::spawn(async move { Self::handler(local, remote, request).await });
+        use crate::response::ResponseCode;
+
+        while let Some((mut local, request)) = self.local.fetch().await {
+            match self.remote.fetch().await {
+                Some(remote) => {
+                    let context = self.context.clone();
+                    tokio::spawn(
+                        async move { Self::handler(local, remote, request, &context).await },
+                    );
+                }
+                None => {
+                    tokio::spawn(async move {
+                        let response = Response::new(ResponseCode::GeneralFailure)
+                            .with_reason("remote unavailable");
+                        local.reply(&response).await
+                    });
+                }
             }
         }
     }
 
-    async fn handler(mut local: LS, mut remote: RS, request: Request) -> Result<()> {
+    async fn handler(
+        mut local: LS,
+        mut remote: RS,
+        request: Request,
+        context: &ClientHandlerContext,
+    ) -> Result<()> {
         use tokio::io::copy_bidirectional;
 
-        use crate::response::Response;
+        use crate::auth::Hello;
         use crate::Streamable;
 
-        request.write(&mut remote).await?;
+        let connect = matches!(request, Request::TCPConnect(_));
+        let bind = matches!(request, Request::TCPBind(_));
+
+        // The local side is told the target is reachable before the server
+        // knows, the response is checked once the remote side sends.
+        let optimistic = connect && (context.optimistic || context.early_data.is_some());
+        if optimistic {
+            local.reply(&Response::succeed()).await?;
+        }
+
+        let mut extensions = context.extensions.clone();
+        if let (true, Some(wait)) = (connect, context.early_data) {
+            let early_data = Self::read_early_data(&mut local, wait).await?;
+            if !early_data.is_empty() {
+                extensions = extensions.with_early_data(early_data);
+            }
+        }
+
+        // The request follows the hello without waiting, the server answers
+        // both at once.
+        let hello = match &context.credentials {
+            Some(credentials) => Hello::new(credentials)?,
+            None => Hello::anonymous(),
+        };
+        hello.write(&mut remote).await?;
 
-        let response = Response::read(&mut remote).await?;
+        let early_data = extensions.early_data().cloned();
+        request
+            .write_with_extensions(extensions, &mut remote)
+            .await?;
 
-        if let Response::Succeed = response {
+        if optimistic {
+            return Self::relay_optimistic(local, remote, early_data).await;
+        }
+
+        let mut response = Self::read_response(&mut remote).await?;
+
+        // A bind is answered twice, the second response arrives once the
+        // server accepted an inbound connection.
+        if bind && response.is_succeed() {
+            local.reply(&response).await?;
+            response = Response::read(&mut remote).await?;
+        }
+
+        local.reply(&response).await?;
+
+        if response.is_succeed() {
             copy_bidirectional(&mut local, &mut remote).await?;
         };
 
         Ok(())
     }
+
+    /// Relays both directions at once, remote bytes only after a succeeded
+    /// response ahead of them. With `early_data` sent along with the request,
+    /// local bytes follow once the response tells whether it was delivered.
+    async fn relay_optimistic(local: LS, remote: RS, early_data: Option<Bytes>) -> Result<()> {
+        use tokio::io::{copy, split, Error};
+        use tokio::sync::oneshot;
+
+        let (mut local_reader, mut local_writer) = split(local);
+        let (mut remote_reader, mut remote_writer) = split(remote);
+        let (delivered, early_data_delivered) = oneshot::channel();
+
+        let uplink = async {
+            if let Some(early_data) = early_data {
+                // Dropped only along with a failed downlink, which ends the relay.
+                if let Ok(false) = early_data_delivered.await {
+                    remote_writer.write_all(&early_data).await?;
+                }
+            }
+
+            copy(&mut local_reader, &mut remote_writer).await?;
+            remote_writer.shutdown().await
+        };
+
+        let downlink = async {
+            let response = Self::read_response(&mut remote_reader).await?;
+            if !response.is_succeed() {
+                return Err(Error::from(&response));
+            }
+            let _ = delivered.send(response.early_data);
+
+            copy(&mut remote_reader, &mut local_writer).await?;
+            local_writer.shutdown().await
+        };
+
+        tokio::try_join!(uplink, downlink)?;
+        Ok(())
+    }
+
+    /// Reads the server's answer to the hello and then its first response.
+    async fn read_response<T>(remote: &mut T) -> Result<Response>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        use crate::auth::ServerHello;
+        use crate::response::ResponseCode;
+        use crate::Streamable;
+
+        ServerHello::read(remote).await?.check()?;
+
+        let response = Response::read(remote).await?;
+
+        if response.code == ResponseCode::AuthenticationFailed {
+            log::warn!("remote rejected the client credentials");
+        }
+
+        Ok(response)
+    }
+
+    /// Whatever the local side sends first within `wait`, empty if nothing.
+    async fn read_early_data(local: &mut LS, wait: Duration) -> Result<Bytes> {
+        let mut buffer = vec![0u8; MAX_EARLY_DATA];
+
+        let len = match tokio::time::timeout(wait, local.read(&mut buffer)).await {
+            Ok(read) => read?,
+            Err(_) => 0,
+        };
+        buffer.truncate(len);
+
+        Ok(buffer.into())
+    }
+}
+
+/// Plain streams have no way to carry a response, a failure just closes them.
+impl Reply for TcpStream {
+    async fn reply(&mut self, _response: &Response) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::auth::{Hello, ServerHello};
+    use crate::Streamable;
+
+    struct NoStreams;
+
+    impl Provider<(TcpStream, Request)> for NoStreams {
+        async fn fetch(&mut self) -> Option<(TcpStream, Request)> {
+            None
+        }
+    }
+
+    impl Provider<DuplexStream> for NoStreams {
+        async fn fetch(&mut self) -> Option<DuplexStream> {
+            None
+        }
+    }
+
+    type TestClient = Client<NoStreams, NoStreams, TcpStream, DuplexStream>;
+
+    /// Relays "hello" as early data and " world" after it to a server that
+    /// acknowledges the early data or not, returning what the server got.
+    async fn relay_early_data(acknowledge: bool) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut app = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (local, _) = listener.accept().await.unwrap();
+        let (remote, mut server) = duplex(1024);
+
+        let context = ClientHandlerContext {
+            credentials: None,
+            extensions: Extensions::new(),
+            early_data: Some(Duration::from_secs(5)),
+            optimistic: false,
+        };
+        let request = Request::TCPConnect(SocketAddr::from(([192, 0, 2, 1], 80)).into());
+        let client =
+            tokio::spawn(
+                async move { TestClient::handler(local, remote, request, &context).await },
+            );
+
+        let (requested, request_read) = oneshot::channel();
+        let server = tokio::spawn(async move {
+            assert!(Hello::read(&mut server).await.unwrap().is_anonymous());
+            ServerHello::new().write(&mut server).await.unwrap();
+
+            let (_, extensions) = Request::read_with_extensions(&mut server).await.unwrap();
+            assert_eq!(extensions.early_data().unwrap().as_ref(), b"hello");
+            requested.send(()).unwrap();
+
+            let mut response = Response::succeed();
+            if acknowledge {
+                response = response.with_early_data();
+            }
+            response.write(&mut server).await.unwrap();
+
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            server.shutdown().await.unwrap();
+            received
+        });
+
+        app.write_all(b"hello").await.unwrap();
+        request_read.await.unwrap();
+        app.write_all(b" world").await.unwrap();
+        app.shutdown().await.unwrap();
+
+        let mut rest = Vec::new();
+        app.read_to_end(&mut rest).await.unwrap();
+        client.await.unwrap().unwrap();
+
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn acknowledged_early_data_is_not_sent_again() {
+        assert_eq!(relay_early_data(true).await, b" world");
+    }
+
+    #[tokio::test]
+    async fn early_data_is_sent_again_without_acknowledgement() {
+        assert_eq!(relay_early_data(false).await, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn server_without_the_client_version_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _app = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (local, _) = listener.accept().await.unwrap();
+        let (remote, mut server) = duplex(1024);
+
+        let context = ClientHandlerContext {
+            credentials: None,
+            extensions: Extensions::new(),
+            early_data: None,
+            optimistic: false,
+        };
+        let request = Request::TCPConnect(SocketAddr::from(([192, 0, 2, 1], 80)).into());
+        let client =
+            tokio::spawn(
+                async move { TestClient::handler(local, remote, request, &context).await },
+            );
+
+        let hello = Hello::read(&mut server).await.unwrap();
+        ServerHello {
+            versions: vec![hello.version + 1],
+        }
+        .write(&mut server)
+        .await
+        .unwrap();
+
+        let error = client.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    }
 }
@@ -0,0 +1,131 @@
+use std::future::poll_fn;
+use std::task::Poll;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::request::{Address, Request};
+use crate::transport::backoff_accept;
+use crate::Provider;
+
+/// Static port forwarding inbound for a `Client`, every connection accepted
+/// on a listener is tunneled to the fixed address it was configured with.
+pub struct ForwardProvider {
+    forwards: Vec<(TcpListener, Address)>,
+    next: usize,
+}
+
+impl ForwardProvider {
+    pub fn new(listener: TcpListener, target: Address) -> Self {
+        Self {
+            forwards: vec![(listener, target)],
+            next: 0,
+        }
+    }
+
+    /// Adds another listener forwarding to `target`.
+    pub fn with_forward(mut self, listener: TcpListener, target: Address) -> Self {
+        self.forwards.push((listener, target));
+        self
+    }
+}
+
+impl Provider<(TcpStream, Request)> for ForwardProvider {
+    async fn fetch(&mut self) -> Option<(TcpStream, Request)> {
+        loop {
+            let (index, accepted) = poll_fn(|cx| {
+                let len = self.forwards.len();
+
+                // Start after the last listener served, so a busy one cannot
+                // starve the others.
+                for offset in 0..len {
+                    let index = (self.next + offset) % len;
+
+                    if let Poll::Ready(accepted) = self.forwards[index].0.poll_accept(cx) {
+                        return Poll::Ready((index, accepted));
+                    }
+                }
+
+                Poll::Pending
+            })
+            .await;
+
+            self.next = index + 1;
+
+            match accepted {
+                Ok((stream, _)) => {
+                    let request = Request::TCPConnect(self.forwards[index].1.clone());
+                    return Some((stream, request));
+                }
+                Err(error) => backoff_accept(&error).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        (listener, address)
+    }
+
+    fn target(request: Request) -> (String, u16) {
+        match request {
+            Request::TCPConnect(Address::Domain(domain, port)) => (domain, port),
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+
+    #[tokio::test]
+    async fn listeners_forward_to_their_own_target() {
+        let (first, first_address) = listen().await;
+        let (second, second_address) = listen().await;
+        let mut provider = ForwardProvider::new(first, Address::Domain("one.example".into(), 1))
+            .with_forward(second, Address::Domain("two.example".into(), 2));
+
+        for (address, expected) in [
+            (second_address, ("two.example", 2)),
+            (first_address, ("one.example", 1)),
+            (second_address, ("two.example", 2)),
+        ] {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            let (mut stream, request) = provider.fetch().await.unwrap();
+
+            let (domain, port) = target(request);
+            assert_eq!((domain.as_str(), port), expected);
+
+            client.write_all(b"ping").await.unwrap();
+            let mut received = [0u8; 4];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"ping");
+        }
+    }
+
+    #[tokio::test]
+    async fn busy_listener_does_not_starve_the_others() {
+        let (first, first_address) = listen().await;
+        let (second, second_address) = listen().await;
+        let mut provider = ForwardProvider::new(first, Address::Domain("one.example".into(), 1))
+            .with_forward(second, Address::Domain("two.example".into(), 2));
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            clients.push(TcpStream::connect(first_address).await.unwrap());
+        }
+        clients.push(TcpStream::connect(second_address).await.unwrap());
+
+        let mut ports = Vec::new();
+        for _ in 0..3 {
+            let (_, request) = provider.fetch().await.unwrap();
+            ports.push(target(request).1);
+        }
+        assert_eq!(ports, [1, 2, 1]);
+    }
+}
@@ -0,0 +1,429 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{Acceptor, Handshake, InboundStream, Protocol};
+use crate::request::{Address, Request};
+use crate::response::{Response, ResponseCode};
+use crate::Provider;
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// HTTP/1.1 proxy inbound for a `Client`, accepting both `CONNECT host:port`
+/// tunnels and absolute-URI forward-proxy requests such as
+/// `GET http://host/path`.
+///
+/// A forward-proxy request head is rewritten to origin-form and replayed into
+/// the tunnel, with `Connection: close` so the connection serves one request.
+pub struct HttpProvider {
+    acceptor: Acceptor<HttpHandshake>,
+}
+
+impl HttpProvider {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            acceptor: Acceptor::new(listener, HttpHandshake::default()),
+        }
+    }
+
+    /// Requires `Proxy-Authorization: Basic`, may be called repeatedly.
+    pub fn with_user<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: AsRef<str>,
+        P: AsRef<str>,
+    {
+        self.acceptor.handshake.insert_user(username, password);
+        self
+    }
+}
+
+impl Provider<(InboundStream, Request)> for HttpProvider {
+    async fn fetch(&mut self) -> Option<(InboundStream, Request)> {
+        self.acceptor.accept().await
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct HttpHandshake {
+    credentials: Arc<Vec<String>>,
+}
+
+impl Handshake for HttpHandshake {
+    async fn handshake(self, mut stream: TcpStream) -> Result<(InboundStream, Request)> {
+        let (head, rest) = read_head(&mut stream).await?;
+
+        let head = match std::str::from_utf8(&head) {
+            Ok(head) => head,
+            Err(_) => {
+                write_status(&mut stream, 400, "Bad Request", None).await?;
+                return Err(Error::other("invalid http request head"));
+            }
+        };
+
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (method, target, version) = match (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) {
+            (Some(method), Some(target), Some(version)) => (method, target, version),
+            _ => {
+                write_status(&mut stream, 400, "Bad Request", None).await?;
+                return Err(Error::other("invalid http request line"));
+            }
+        };
+
+        let headers: Vec<(&str, &str)> = lines
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+
+        if !self.credentials.is_empty() && !self.authorized(&headers) {
+            write_status(
+                &mut stream,
+                407,
+                "Proxy Authentication Required",
+                Some("Proxy-Authenticate: Basic realm=\"quicos\""),
+            )
+            .await?;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "http proxy authentication failed",
+            ));
+        }
+
+        if method.eq_ignore_ascii_case("CONNECT") {
+            let Some(address) = parse_authority(target, 443) else {
+                write_status(&mut stream, 400, "Bad Request", None).await?;
+                return Err(Error::other(format!("invalid connect target {}", target)));
+            };
+
+            let inbound = InboundStream::new(stream, Protocol::HttpConnect).with_buffer(rest);
+            return Ok((inbound, Request::TCPConnect(address)));
+        }
+
+        let Some((authority, path)) = target
+            .strip_prefix("http://")
+            .map(|target| target.split_at(target.find('/').unwrap_or(target.len())))
+        else {
+            write_status(&mut stream, 400, "Bad Request", None).await?;
+            return Err(Error::other(format!("unsupported proxy target {}", target)));
+        };
+
+        let Some(address) = parse_authority(authority, 80) else {
+            write_status(&mut stream, 400, "Bad Request", None).await?;
+            return Err(Error::other(format!("invalid proxy target {}", target)));
+        };
+
+        let path = if path.is_empty() { "/" } else { path };
+
+        // The request target overrides whatever `Host` came with it, as in
+        // RFC 9112 section 3.2.2.
+        let mut rewritten = format!("{} {} {}\r\n", method, path, version);
+        rewritten.push_str(&format!("Host: {}\r\n", authority));
+        for (name, value) in headers.iter().filter(|(name, _)| {
+            !name.eq_ignore_ascii_case("Host")
+                && !name.eq_ignore_ascii_case("Connection")
+                && !name.eq_ignore_ascii_case("Keep-Alive")
+                && !name.to_ascii_lowercase().starts_with("proxy-")
+        }) {
+            rewritten.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        rewritten.push_str("Connection: close\r\n\r\n");
+
+        let mut buffer = BytesMut::from(rewritten.as_bytes());
+        buffer.extend_from_slice(&rest);
+
+        let inbound =
+            InboundStream::new(stream, Protocol::HttpForward).with_buffer(buffer.freeze());
+        Ok((inbound, Request::TCPConnect(address)))
+    }
+}
+
+impl HttpHandshake {
+    pub(crate) fn insert_user<U, P>(&mut self, username: U, password: P)
+    where
+        U: AsRef<str>,
+        P: AsRef<str>,
+    {
+        let credentials = format!("{}:{}", username.as_ref(), password.as_ref());
+        Arc::make_mut(&mut self.credentials).push(base64_encode(credentials.as_bytes()));
+    }
+
+    fn authorized(&self, headers: &[(&str, &str)]) -> bool {
+        headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Proxy-Authorization"))
+            .filter_map(|(_, value)| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+            .any(|(_, token)| self.credentials.iter().any(|expected| expected == token))
+    }
+}
+
+/// Reads up to and including the blank line ending the request head, returns
+/// the head and whatever was read past it.
+async fn read_head(stream: &mut TcpStream) -> Result<(Bytes, Bytes)> {
+    let mut buffer = BytesMut::with_capacity(1024);
+
+    loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = buffer.split_to(end + 4).freeze();
+            return Ok((head, buffer.freeze()));
+        }
+
+        if buffer.len() >= MAX_HEAD_SIZE {
+            write_status(stream, 431, "Request Header Fields Too Large", None).await?;
+            return Err(Error::other("http request head too large"));
+        }
+
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "http request head incomplete",
+            ));
+        }
+    }
+}
+
+fn parse_authority(authority: &str, default_port: u16) -> Option<Address> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+        _ => (authority, default_port),
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+
+    let address = match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).into(),
+        Err(_) => Address::Domain(host.to_string(), port),
+    };
+
+    Some(address)
+}
+
+pub(crate) async fn reply(
+    stream: &mut TcpStream,
+    response: &Response,
+    connect: bool,
+) -> Result<()> {
+    let (status, reason) = match response.code {
+        ResponseCode::Succeed if connect => (200, "Connection Established"),
+        ResponseCode::Succeed => return Ok(()),
+        ResponseCode::RulesetDenied => (403, "Forbidden"),
+        ResponseCode::TtlExpired => (504, "Gateway Timeout"),
+        ResponseCode::UnsupportedRequest => (501, "Not Implemented"),
+        // The tunnel refused this proxy, not the client nor the upstream.
+        ResponseCode::AuthenticationFailed => (500, "Internal Server Error"),
+        _ => (502, "Bad Gateway"),
+    };
+
+    match &response.reason {
+        Some(detail) if status != 200 => {
+            let head = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                reason,
+                detail.len()
+            );
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(detail.as_bytes()).await
+        }
+        _ => write_status(stream, status, reason, None).await,
+    }
+}
+
+async fn write_status(
+    stream: &mut TcpStream,
+    status: u16,
+    reason: &str,
+    header: Option<&str>,
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    if let Some(header) = header {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    if status != 200 {
+        head.push_str("Content-Length: 0\r\nConnection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await
+}
+
+fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let triple = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => output.push(ALPHABET[(triple >> (18 - 6 * i) & 0x3F) as usize] as char),
+                false => output.push('='),
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::Reply;
+
+    /// Sends `head` to a provider and returns its result with the client.
+    async fn fetch(
+        provider: impl FnOnce(TcpListener) -> HttpProvider,
+        head: &str,
+    ) -> (Option<(InboundStream, Request)>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut provider = provider(listener);
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(head.as_bytes()).await.unwrap();
+
+        let fetched = tokio::time::timeout(Duration::from_secs(1), provider.fetch())
+            .await
+            .ok()
+            .flatten();
+        (fetched, client)
+    }
+
+    async fn read_response(client: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connect_tunnels_to_the_authority() {
+        let head = "CONNECT example.com:8443 HTTP/1.1\r\nHost: example.com:8443\r\n\r\nearly";
+        let (fetched, mut client) = fetch(HttpProvider::new, head).await;
+
+        let (mut inbound, request) = fetched.unwrap();
+        let Request::TCPConnect(Address::Domain(domain, port)) = request else {
+            panic!("expected a domain connect");
+        };
+        assert_eq!((domain.as_str(), port), ("example.com", 8443));
+
+        let mut early = [0u8; 5];
+        inbound.read_exact(&mut early).await.unwrap();
+        assert_eq!(&early, b"early");
+
+        inbound.reply(&Response::succeed()).await.unwrap();
+        drop(inbound);
+        assert_eq!(
+            read_response(&mut client).await,
+            "HTTP/1.1 200 Connection Established\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_requests_are_rewritten_to_origin_form() {
+        let head = "GET http://192.0.2.1:8080/index.html?q=1 HTTP/1.1\r\n\
+                    Host: elsewhere.example\r\n\
+                    Proxy-Authorization: Basic Zm9vOmJhcg==\r\n\
+                    Proxy-Connection: keep-alive\r\n\
+                    Connection: keep-alive\r\n\
+                    Keep-Alive: timeout=5\r\n\
+                    Accept: */*\r\n\r\n";
+        let (fetched, _client) = fetch(HttpProvider::new, head).await;
+
+        let (mut inbound, request) = fetched.unwrap();
+        let Request::TCPConnect(Address::IPv4(address)) = request else {
+            panic!("expected an IPv4 connect");
+        };
+        assert_eq!(address.to_string(), "192.0.2.1:8080");
+
+        let mut rewritten = vec![0u8; inbound.buffer.len()];
+        inbound.read_exact(&mut rewritten).await.unwrap();
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            "GET /index.html?q=1 HTTP/1.1\r\n\
+             Host: 192.0.2.1:8080\r\n\
+             Accept: */*\r\n\
+             Connection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_credentials_are_challenged() {
+        let with_user = |listener| HttpProvider::new(listener).with_user("foo", "bar");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut provider = with_user(listener);
+        let fetching = tokio::spawn(async move { provider.fetch().await });
+
+        let head = "CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+        client.write_all(head.as_bytes()).await.unwrap();
+        assert_eq!(
+            read_response(&mut client).await,
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+             Proxy-Authenticate: Basic realm=\"quicos\"\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+        assert!(!fetching.is_finished());
+        fetching.abort();
+
+        let head = "CONNECT example.com:443 HTTP/1.1\r\n\
+                    Proxy-Authorization: Basic Zm9vOmJhcg==\r\n\r\n";
+        let (fetched, _client) = fetch(with_user, head).await;
+        assert!(fetched.is_some());
+    }
+
+    #[tokio::test]
+    async fn failures_map_to_statuses() {
+        let cases = [
+            (ResponseCode::RulesetDenied, "403 Forbidden"),
+            (ResponseCode::TtlExpired, "504 Gateway Timeout"),
+            (ResponseCode::UnsupportedRequest, "501 Not Implemented"),
+            (
+                ResponseCode::AuthenticationFailed,
+                "500 Internal Server Error",
+            ),
+            (ResponseCode::ConnectionRefused, "502 Bad Gateway"),
+            (ResponseCode::DnsFailure, "502 Bad Gateway"),
+        ];
+
+        for (code, status) in cases {
+            let head = "CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+            let (fetched, mut client) = fetch(HttpProvider::new, head).await;
+
+            let (mut inbound, _) = fetched.unwrap();
+            inbound.reply(&Response::new(code)).await.unwrap();
+            drop(inbound);
+
+            let response = read_response(&mut client).await;
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}\r\n", status)),
+                "{:?} answered with {}",
+                code,
+                response
+            );
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::io::{Error, ErrorKind, Result};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use super::http::HttpHandshake;
+use super::socks5::Socks5Handshake;
+use super::{socks4, Acceptor, Handshake, InboundStream, Protocol};
+use crate::request::Request;
+use crate::Provider;
+
+#[rustfmt::skip]
+mod consts_mixed {
+    pub const SOCKS4_VERSION: u8 = 0x04;
+    pub const SOCKS5_VERSION: u8 = 0x05;
+}
+
+/// Serves SOCKS4/4a, SOCKS5 and HTTP proxy clients on one listener. The
+/// protocol is told apart by peeking the first byte, which stays in the
+/// socket for the protocol handshake to read.
+pub struct MixedProvider {
+    acceptor: Acceptor<MixedHandshake>,
+}
+
+impl MixedProvider {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            acceptor: Acceptor::new(listener, MixedHandshake::default()),
+        }
+    }
+
+    /// Requires authentication for SOCKS5 and HTTP, and refuses SOCKS4 which
+    /// cannot carry a password. May be called repeatedly.
+    pub fn with_user<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let (username, password) = (username.into(), password.into());
+
+        let handshake = &mut self.acceptor.handshake;
+        handshake.http.insert_user(&username, &password);
+        handshake.socks5.insert_user(username, password);
+        self
+    }
+}
+
+impl Provider<(InboundStream, Request)> for MixedProvider {
+    async fn fetch(&mut self) -> Option<(InboundStream, Request)> {
+        self.acceptor.accept().await
+    }
+}
+
+#[derive(Clone, Default)]
+struct MixedHandshake {
+    socks5: Socks5Handshake,
+    http: HttpHandshake,
+}
+
+impl Handshake for MixedHandshake {
+    async fn handshake(self, mut stream: TcpStream) -> Result<(InboundStream, Request)> {
+        let mut version = [0u8; 1];
+        if stream.peek(&mut version).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before handshake",
+            ));
+        }
+
+        match version[0] {
+            consts_mixed::SOCKS4_VERSION => {
+                let request = socks4::handshake(&mut stream, self.socks5.requires_auth()).await?;
+                Ok((InboundStream::new(stream, Protocol::Socks4), request))
+            }
+            consts_mixed::SOCKS5_VERSION => self.socks5.handshake(stream).await,
+            _ => self.http.handshake(stream).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::request::Address;
+
+    /// Runs the handshake on what a client `sent`, keeping the client open.
+    async fn run(
+        handshake: MixedHandshake,
+        sent: &[u8],
+    ) -> (Result<(InboundStream, Request)>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        client.write_all(sent).await.unwrap();
+        (handshake.handshake(stream).await, client)
+    }
+
+    fn port_80(request: Request) -> bool {
+        matches!(
+            request,
+            Request::TCPConnect(Address::IPv4(address)) if address.port() == 80
+        )
+    }
+
+    #[tokio::test]
+    async fn first_byte_picks_the_protocol() {
+        #[rustfmt::skip]
+        let cases: [(&[u8], Protocol); 4] = [
+            (&[0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1, 0x00], Protocol::Socks4),
+            (&[0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50], Protocol::Socks5),
+            (b"CONNECT 127.0.0.1:80 HTTP/1.1\r\n\r\n", Protocol::HttpConnect),
+            (b"GET http://127.0.0.1/ HTTP/1.1\r\n\r\n", Protocol::HttpForward),
+        ];
+
+        for (sent, protocol) in cases {
+            let (result, _client) = run(MixedHandshake::default(), sent).await;
+
+            let (inbound, request) = result.unwrap();
+            assert_eq!(inbound.protocol, protocol);
+            assert!(port_80(request), "{:?} request mismatch", protocol);
+        }
+    }
+
+    #[tokio::test]
+    async fn socks4_is_refused_with_users() {
+        let mut handshake = MixedHandshake::default();
+        handshake.socks5.insert_user("alice", "secret");
+
+        let sent = [0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1, 0x00];
+        let (result, mut client) = run(handshake, &sent).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x00, 0x5B]);
+    }
+
+    #[tokio::test]
+    async fn closed_before_the_first_byte() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        drop(TcpStream::connect(listener.local_addr().unwrap()).await);
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let error = MixedHandshake::default()
+            .handshake(stream)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
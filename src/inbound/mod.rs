@@ -0,0 +1,146 @@
+use std::future::Future;
+use std::io::Result;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::transport::handshake::Handshakes;
+use crate::transport::tcp::TcpAcceptProvider;
+use crate::Reply;
+
+pub mod forward;
+pub mod http;
+pub mod mixed;
+mod socks4;
+pub mod socks5;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Socks4,
+    Socks5,
+    HttpConnect,
+    HttpForward,
+}
+
+/// A local connection accepted by one of the inbound providers, past its
+/// proxy handshake. The tunnel [`Response`] is answered in the protocol the
+/// connection was accepted with.
+///
+/// Bytes the handshake read past its own end, or rewrote, are returned by
+/// the first reads before anything else from the connection.
+#[derive(Debug)]
+pub struct InboundStream {
+    stream: TcpStream,
+    protocol: Protocol,
+    buffer: Bytes,
+}
+
+impl InboundStream {
+    pub(crate) fn new(stream: TcpStream, protocol: Protocol) -> Self {
+        Self {
+            stream,
+            protocol,
+            buffer: Bytes::new(),
+        }
+    }
+
+    pub(crate) fn with_buffer(mut self, buffer: Bytes) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl Reply for InboundStream {
+    async fn reply(&mut self, response: &Response) -> Result<()> {
+        match self.protocol {
+            Protocol::Socks4 => socks4::reply(&mut self.stream, response).await,
+            Protocol::Socks5 => socks5::reply(&mut self.stream, response).await,
+            Protocol::HttpConnect => http::reply(&mut self.stream, response, true).await,
+            Protocol::HttpForward => http::reply(&mut self.stream, response, false).await,
+        }
+    }
+}
+
+impl AsyncRead for InboundStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        if self.buffer.has_remaining() {
+            let len = self.buffer.len().min(buf.remaining());
+            buf.put_slice(&self.buffer[..len]);
+            self.buffer.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for InboundStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+pub(crate) trait Handshake: Clone + Send + Sync + 'static {
+    fn handshake(
+        self,
+        stream: TcpStream,
+    ) -> impl Future<Output = Result<(InboundStream, Request)>> + Send;
+}
+
+/// Accepts connections and runs their handshakes concurrently, so a slow
+/// client does not hold up the listener.
+pub(crate) struct Acceptor<H> {
+    listener: TcpAcceptProvider,
+    pub(crate) handshake: H,
+    handshakes: Handshakes<(InboundStream, Request)>,
+}
+
+impl<H> Acceptor<H>
+where
+    H: Handshake,
+{
+    pub(crate) fn new(listener: TcpListener, handshake: H) -> Self {
+        Self {
+            listener: TcpAcceptProvider::new(listener),
+            handshake,
+            handshakes: Handshakes::new(),
+        }
+    }
+
+    pub(crate) async fn accept(&mut self) -> Option<(InboundStream, Request)> {
+        let handshake = &self.handshake;
+
+        self.handshakes
+            .next(&mut self.listener, |stream| {
+                handshake.clone().handshake(stream)
+            })
+            .await
+    }
+}
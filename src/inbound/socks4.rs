@@ -0,0 +1,116 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::request::{Address, Request};
+use crate::response::Response;
+
+#[rustfmt::skip]
+mod consts_socks4 {
+    pub const VERSION:         u8 = 0x04;
+    pub const REPLY_VERSION:   u8 = 0x00;
+
+    pub const COMMAND_CONNECT: u8 = 0x01;
+    pub const COMMAND_BIND:    u8 = 0x02;
+
+    pub const REPLY_GRANTED:   u8 = 0x5A;
+    pub const REPLY_REJECTED:  u8 = 0x5B;
+}
+
+const MAX_FIELD_SIZE: usize = 255;
+
+/// SOCKS4 and its 4a extension, where a `DSTIP` of `0.0.0.x` means a domain
+/// name follows the user id. SOCKS4 carries no password, so it is refused
+/// when `authenticated` users are required.
+pub(crate) async fn handshake(stream: &mut TcpStream, authenticated: bool) -> Result<Request> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+
+    if header[0] != consts_socks4::VERSION {
+        return Err(Error::other(format!(
+            "unsupported socks version {}",
+            header[0]
+        )));
+    }
+
+    let port = u16::from_be_bytes([header[2], header[3]]);
+    let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+
+    read_field(stream).await?;
+
+    let address = match ip.octets() {
+        [0, 0, 0, last] if last != 0 => Address::Domain(read_field(stream).await?, port),
+        _ => Address::IPv4(SocketAddrV4::new(ip, port)),
+    };
+
+    if authenticated {
+        write_reply(stream, consts_socks4::REPLY_REJECTED, None).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "socks4 cannot authenticate",
+        ));
+    }
+
+    let request = match header[1] {
+        consts_socks4::COMMAND_CONNECT => Request::TCPConnect(address),
+        consts_socks4::COMMAND_BIND => Request::TCPBind(address),
+        command => {
+            write_reply(stream, consts_socks4::REPLY_REJECTED, None).await?;
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported socks command {}", command),
+            ));
+        }
+    };
+
+    Ok(request)
+}
+
+/// Reads a null terminated string.
+async fn read_field(stream: &mut TcpStream) -> Result<String> {
+    let mut buffer = Vec::new();
+
+    loop {
+        match stream.read_u8().await? {
+            0 => break,
+            _ if buffer.len() >= MAX_FIELD_SIZE => {
+                return Err(Error::other("socks4 field too long"))
+            }
+            byte => buffer.push(byte),
+        }
+    }
+
+    String::from_utf8(buffer).map_err(|_| Error::other("invalid socks4 field"))
+}
+
+pub(crate) async fn reply(stream: &mut TcpStream, response: &Response) -> Result<()> {
+    let code = match response.is_succeed() {
+        true => consts_socks4::REPLY_GRANTED,
+        false => consts_socks4::REPLY_REJECTED,
+    };
+
+    let address = match &response.address {
+        Some(Address::IPv4(addr)) => Some(*addr),
+        _ => None,
+    };
+
+    write_reply(stream, code, address).await
+}
+
+async fn write_reply(
+    stream: &mut TcpStream,
+    code: u8,
+    address: Option<SocketAddrV4>,
+) -> Result<()> {
+    let address = address.unwrap_or(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+    let mut bytes = [0u8; 8];
+    bytes[0] = consts_socks4::REPLY_VERSION;
+    bytes[1] = code;
+    bytes[2..4].copy_from_slice(&address.port().to_be_bytes());
+    bytes[4..8].copy_from_slice(&address.ip().octets());
+
+    stream.write_all(&bytes).await
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{Acceptor, Handshake, InboundStream, Protocol};
+use crate::request::{Address, Request};
+use crate::response::{Response, ResponseCode};
+use crate::Provider;
+
+#[rustfmt::skip]
+mod consts_socks5 {
+    pub const VERSION:                u8 = 0x05;
+    pub const AUTH_VERSION:           u8 = 0x01;
+
+    pub const METHOD_NO_AUTH:         u8 = 0x00;
+    pub const METHOD_PASSWORD:        u8 = 0x02;
+    pub const METHOD_NO_ACCEPTABLE:   u8 = 0xFF;
+
+    pub const AUTH_SUCCEED:           u8 = 0x00;
+    pub const AUTH_FAILURE:           u8 = 0x01;
+
+    pub const COMMAND_CONNECT:        u8 = 0x01;
+    pub const COMMAND_BIND:           u8 = 0x02;
+
+    pub const ADDRESS_TYPE_IPV4:      u8 = 0x01;
+    pub const ADDRESS_TYPE_DOMAIN:    u8 = 0x03;
+    pub const ADDRESS_TYPE_IPV6:      u8 = 0x04;
+
+    pub const REPLY_SUCCEED:          u8 = 0x00;
+    pub const REPLY_GENERAL_FAILURE:  u8 = 0x01;
+    pub const REPLY_NOT_ALLOWED:      u8 = 0x02;
+    pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+    pub const REPLY_REFUSED:          u8 = 0x05;
+    pub const REPLY_TTL_EXPIRED:      u8 = 0x06;
+    pub const REPLY_NOT_SUPPORTED:    u8 = 0x07;
+    pub const REPLY_ADDRESS_TYPE:     u8 = 0x08;
+}
+
+/// SOCKS5 ([RFC 1928]) inbound for a `Client`, supporting `CONNECT` and
+/// `BIND` with either no authentication or username/password ([RFC 1929]).
+///
+/// [RFC 1928]: https://www.rfc-editor.org/rfc/rfc1928
+/// [RFC 1929]: https://www.rfc-editor.org/rfc/rfc1929
+pub struct Socks5Provider {
+    acceptor: Acceptor<Socks5Handshake>,
+}
+
+impl Socks5Provider {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            acceptor: Acceptor::new(listener, Socks5Handshake::default()),
+        }
+    }
+
+    /// Requires username/password authentication, may be called repeatedly.
+    pub fn with_user<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.acceptor.handshake.insert_user(username, password);
+        self
+    }
+}
+
+impl Provider<(InboundStream, Request)> for Socks5Provider {
+    async fn fetch(&mut self) -> Option<(InboundStream, Request)> {
+        self.acceptor.accept().await
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Socks5Handshake {
+    users: Arc<HashMap<String, String>>,
+}
+
+impl Socks5Handshake {
+    pub(crate) fn insert_user<U, P>(&mut self, username: U, password: P)
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        Arc::make_mut(&mut self.users).insert(username.into(), password.into());
+    }
+
+    pub(crate) fn requires_auth(&self) -> bool {
+        !self.users.is_empty()
+    }
+}
+
+impl Handshake for Socks5Handshake {
+    async fn handshake(self, mut stream: TcpStream) -> Result<(InboundStream, Request)> {
+        let request = handshake(&mut stream, &self.users).await?;
+
+        Ok((InboundStream::new(stream, Protocol::Socks5), request))
+    }
+}
+
+async fn handshake<S>(stream: &mut S, users: &HashMap<String, String>) -> Result<Request>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = stream.read_u8().await?;
+    if version != consts_socks5::VERSION {
+        return Err(Error::other(format!(
+            "unsupported socks version {}",
+            version
+        )));
+    }
+
+    let methods_len = stream.read_u8().await? as usize;
+    let mut methods = vec![0u8; methods_len];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match users.is_empty() {
+        true => consts_socks5::METHOD_NO_AUTH,
+        false => consts_socks5::METHOD_PASSWORD,
+    };
+
+    if !methods.contains(&method) {
+        stream
+            .write_all(&[consts_socks5::VERSION, consts_socks5::METHOD_NO_ACCEPTABLE])
+            .await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "no acceptable socks method",
+        ));
+    }
+
+    stream.write_all(&[consts_socks5::VERSION, method]).await?;
+
+    if method == consts_socks5::METHOD_PASSWORD {
+        authenticate(stream, users).await?;
+    }
+
+    let mut header = [0u8; 3];
+    stream.read_exact(&mut header).await?;
+
+    if header[0] != consts_socks5::VERSION || header[2] != 0x00 {
+        write_reply(stream, consts_socks5::REPLY_GENERAL_FAILURE, None).await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("malformed socks request header {:02x?}", header),
+        ));
+    }
+
+    let address = match read_address(stream).await {
+        Ok(address) => address,
+        Err(error) => {
+            write_reply(stream, consts_socks5::REPLY_ADDRESS_TYPE, None).await?;
+            return Err(error);
+        }
+    };
+
+    let request = match header[1] {
+        consts_socks5::COMMAND_CONNECT => Request::TCPConnect(address),
+        consts_socks5::COMMAND_BIND => Request::TCPBind(address),
+        command => {
+            write_reply(stream, consts_socks5::REPLY_NOT_SUPPORTED, None).await?;
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported socks command {}", command),
+            ));
+        }
+    };
+
+    Ok(request)
+}
+
+async fn authenticate<S>(stream: &mut S, users: &HashMap<String, String>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = stream.read_u8().await?;
+    if version != consts_socks5::AUTH_VERSION {
+        return Err(Error::other(format!(
+            "unsupported socks auth version {}",
+            version
+        )));
+    }
+
+    let username_len = stream.read_u8().await? as usize;
+    let mut username = vec![0u8; username_len];
+    stream.read_exact(&mut username).await?;
+
+    let password_len = stream.read_u8().await? as usize;
+    let mut password = vec![0u8; password_len];
+    stream.read_exact(&mut password).await?;
+
+    let accepted = std::str::from_utf8(&username)
+        .ok()
+        .and_then(|username| users.get(username))
+        .is_some_and(|expected| expected.as_bytes() == password);
+
+    if !accepted {
+        stream
+            .write_all(&[consts_socks5::AUTH_VERSION, consts_socks5::AUTH_FAILURE])
+            .await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "socks authentication failed",
+        ));
+    }
+
+    stream
+        .write_all(&[consts_socks5::AUTH_VERSION, consts_socks5::AUTH_SUCCEED])
+        .await
+}
+
+async fn read_address<S>(stream: &mut S) -> Result<Address>
+where
+    S: AsyncRead + Unpin,
+{
+    let address_type = stream.read_u8().await?;
+
+    let address = match address_type {
+        consts_socks5::ADDRESS_TYPE_IPV4 => {
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).await?;
+            let port = stream.read_u16().await?;
+
+            Address::IPv4(SocketAddrV4::new(Ipv4Addr::from(buffer), port))
+        }
+
+        consts_socks5::ADDRESS_TYPE_DOMAIN => {
+            let domain_len = stream.read_u8().await? as usize;
+            let mut buffer = vec![0u8; domain_len];
+            stream.read_exact(&mut buffer).await?;
+            let port = stream.read_u16().await?;
+
+            let domain =
+                String::from_utf8(buffer).map_err(|_| Error::other("invalid domain name"))?;
+
+            Address::Domain(domain, port)
+        }
+
+        consts_socks5::ADDRESS_TYPE_IPV6 => {
+            let mut buffer = [0u8; 16];
+            stream.read_exact(&mut buffer).await?;
+            let port = stream.read_u16().await?;
+
+            Address::IPv6(SocketAddrV6::new(Ipv6Addr::from(buffer), port, 0, 0))
+        }
+
+        _ => {
+            return Err(Error::other(format!(
+                "unsupported socks address type {}",
+                address_type
+            )))
+        }
+    };
+
+    Ok(address)
+}
+
+pub(crate) async fn reply(stream: &mut TcpStream, response: &Response) -> Result<()> {
+    let code = match response.code {
+        ResponseCode::Succeed => consts_socks5::REPLY_SUCCEED,
+        ResponseCode::RulesetDenied => consts_socks5::REPLY_NOT_ALLOWED,
+        ResponseCode::HostUnreachable | ResponseCode::DnsFailure => {
+            consts_socks5::REPLY_HOST_UNREACHABLE
+        }
+        ResponseCode::ConnectionRefused => consts_socks5::REPLY_REFUSED,
+        ResponseCode::TtlExpired => consts_socks5::REPLY_TTL_EXPIRED,
+        ResponseCode::UnsupportedRequest => consts_socks5::REPLY_NOT_SUPPORTED,
+        _ => consts_socks5::REPLY_GENERAL_FAILURE,
+    };
+
+    write_reply(stream, code, response.address.as_ref()).await
+}
+
+async fn write_reply<S>(stream: &mut S, code: u8, address: Option<&Address>) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut bytes = BytesMut::new();
+
+    bytes.put_u8(consts_socks5::VERSION);
+    bytes.put_u8(code);
+    bytes.put_u8(0x00);
+
+    match address {
+        Some(Address::Domain(domain, port)) => {
+            bytes.put_u8(consts_socks5::ADDRESS_TYPE_DOMAIN);
+            bytes.put_u8(domain.len() as u8);
+            bytes.extend_from_slice(domain.as_bytes());
+            bytes.put_u16(*port);
+        }
+        Some(Address::IPv6(addr)) => {
+            bytes.put_u8(consts_socks5::ADDRESS_TYPE_IPV6);
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.put_u16(addr.port());
+        }
+        Some(Address::IPv4(addr)) => {
+            bytes.put_u8(consts_socks5::ADDRESS_TYPE_IPV4);
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.put_u16(addr.port());
+        }
+        None => {
+            bytes.put_u8(consts_socks5::ADDRESS_TYPE_IPV4);
+            bytes.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+            bytes.put_u16(0);
+        }
+    }
+
+    stream.write_all(&bytes).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Runs the handshake on what the client `sent`, returning its outcome
+    /// and everything written back to the client.
+    async fn run(users: &[(&str, &str)], sent: &[u8]) -> (Result<Request>, Vec<u8>) {
+        let users = users
+            .iter()
+            .map(|(username, password)| (username.to_string(), password.to_string()))
+            .collect();
+
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(sent).await.unwrap();
+
+        let result = handshake(&mut server, &users).await;
+        drop(server);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        (result, received)
+    }
+
+    const CONNECT_IPV4: &[u8] = &[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50];
+
+    #[tokio::test]
+    async fn connect_without_authentication() {
+        let sent = [&[0x05, 0x01, 0x00][..], CONNECT_IPV4].concat();
+        let (result, received) = run(&[], &sent).await;
+
+        let Request::TCPConnect(Address::IPv4(address)) = result.unwrap() else {
+            panic!("expected an IPv4 connect");
+        };
+        assert_eq!(address.to_string(), "127.0.0.1:80");
+        assert_eq!(received, [0x05, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn bind_with_username_and_password() {
+        #[rustfmt::skip]
+        let sent = [
+            &[0x05, 0x02, 0x00, 0x02][..],
+            &[0x01, 0x05], b"alice", &[0x06], b"secret",
+            &[0x05, 0x02, 0x00, 0x03, 0x0B], b"example.com", &[0x01, 0xBB],
+        ]
+        .concat();
+        let (result, received) = run(&[("alice", "secret")], &sent).await;
+
+        let Request::TCPBind(Address::Domain(domain, port)) = result.unwrap() else {
+            panic!("expected a domain bind");
+        };
+        assert_eq!((domain.as_str(), port), ("example.com", 443));
+        assert_eq!(received, [0x05, 0x02, 0x01, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_rejected() {
+        #[rustfmt::skip]
+        let sent = [
+            &[0x05, 0x01, 0x02][..],
+            &[0x01, 0x05], b"alice", &[0x05], b"wrong",
+            CONNECT_IPV4,
+        ]
+        .concat();
+        let (result, received) = run(&[("alice", "secret")], &sent).await;
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(received, [0x05, 0x02, 0x01, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn no_acceptable_method() {
+        // Only no authentication is offered to a listener requiring a password.
+        let sent = [&[0x05, 0x01, 0x00][..], CONNECT_IPV4].concat();
+        let (result, received) = run(&[("alice", "secret")], &sent).await;
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(received, [0x05, 0xFF]);
+    }
+
+    #[tokio::test]
+    async fn udp_associate_is_not_supported() {
+        let sent = [
+            &[0x05, 0x01, 0x00][..],
+            &[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+        ]
+        .concat();
+        let (result, received) = run(&[], &sent).await;
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(received[..4], [0x05, 0x00, 0x05, 0x07]);
+    }
+
+    #[tokio::test]
+    async fn malformed_request_header_is_rejected() {
+        // A request of another version, and one with a reserved byte set.
+        for header in [[0x04, 0x01, 0x00], [0x05, 0x01, 0x01]] {
+            let sent = [&[0x05, 0x01, 0x00][..], &header, &CONNECT_IPV4[3..]].concat();
+            let (result, received) = run(&[], &sent).await;
+
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+            assert_eq!(received[..5], [0x05, 0x00, 0x05, 0x01, 0x00]);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem::{size_of, MaybeUninit};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::AsRawFd;
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::request::Request;
+use crate::transport::backoff_accept;
+use crate::Provider;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparentMode {
+    /// Connections redirected by `iptables -j REDIRECT`, the destination is
+    /// read from conntrack with `SO_ORIGINAL_DST`. Connections that were not
+    /// redirected, e.g. made to the listener directly, are dropped.
+    Redirect,
+    /// Connections diverted by `iptables -j TPROXY` to a listener bound with
+    /// `IP_TRANSPARENT`, see [`TransparentProvider::bind_tproxy`]. The local
+    /// address is the original destination.
+    TProxy,
+}
+
+/// Linux transparent proxy inbound for a `Client`, recovering the original
+/// destination of intercepted connections.
+pub struct TransparentProvider {
+    listener: TcpListener,
+    mode: TransparentMode,
+    original_dst: fn(&TcpStream, SocketAddr) -> Result<SocketAddr>,
+}
+
+impl TransparentProvider {
+    pub fn new(listener: TcpListener, mode: TransparentMode) -> Self {
+        Self {
+            listener,
+            mode,
+            original_dst,
+        }
+    }
+
+    /// Binds a listener with `IP_TRANSPARENT` set, requires `CAP_NET_ADMIN`.
+    pub fn bind_tproxy(address: SocketAddr) -> Result<TcpListener> {
+        let socket = match address {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        let (level, name) = match address {
+            SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+            SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+        };
+
+        let enable: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &enable as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        socket.set_reuseaddr(true)?;
+        socket.bind(address)?;
+        socket.listen(1024)
+    }
+
+    fn original_destination(&self, stream: &TcpStream) -> Result<SocketAddr> {
+        let local = stream.local_addr()?;
+
+        match self.mode {
+            TransparentMode::TProxy => Ok(local),
+            TransparentMode::Redirect => match (self.original_dst)(stream, local)? {
+                // Conntrack answers with the listener itself for connections
+                // made to it directly, tunnelling those would loop back here.
+                destination if destination == local => Err(Error::new(
+                    ErrorKind::NotFound,
+                    "connection was not redirected",
+                )),
+                destination => Ok(destination),
+            },
+        }
+    }
+}
+
+impl Provider<(TcpStream, Request)> for TransparentProvider {
+    async fn fetch(&mut self) -> Option<(TcpStream, Request)> {
+        loop {
+            let (stream, _) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    backoff_accept(&error).await;
+                    continue;
+                }
+            };
+
+            if let Ok(destination) = self.original_destination(&stream) {
+                return Some((stream, Request::TCPConnect(destination.into())));
+            }
+        }
+    }
+}
+
+fn original_dst(stream: &TcpStream, local: SocketAddr) -> Result<SocketAddr> {
+    let fd = stream.as_raw_fd();
+
+    if local.is_ipv6() {
+        let mut address = MaybeUninit::<libc::sockaddr_in6>::zeroed();
+        let mut len = size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_IPV6,
+                libc::IP6T_SO_ORIGINAL_DST,
+                address.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+
+        if result == 0 {
+            let address = unsafe { address.assume_init() };
+            return Ok(SocketAddrV6::new(
+                Ipv6Addr::from(address.sin6_addr.s6_addr),
+                u16::from_be(address.sin6_port),
+                address.sin6_flowinfo,
+                address.sin6_scope_id,
+            )
+            .into());
+        }
+    }
+
+    let mut address = MaybeUninit::<libc::sockaddr_in>::zeroed();
+    let mut len = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_IP,
+            libc::SO_ORIGINAL_DST,
+            address.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let address = unsafe { address.assume_init() };
+    Ok(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+        u16::from_be(address.sin_port),
+    )
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::request::Address;
+
+    async fn listen(mode: TransparentMode) -> (TransparentProvider, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        (TransparentProvider::new(listener, mode), address)
+    }
+
+    async fn destination(provider: &mut TransparentProvider, address: SocketAddr) -> SocketAddr {
+        let _client = TcpStream::connect(address).await.unwrap();
+
+        match provider.fetch().await {
+            Some((_, Request::TCPConnect(Address::IPv4(destination)))) => destination.into(),
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+
+    #[tokio::test]
+    async fn redirect_drops_connections_that_were_not_redirected() {
+        let (mut provider, address) = listen(TransparentMode::Redirect).await;
+        let mut client = TcpStream::connect(address).await.unwrap();
+
+        let fetched = tokio::time::timeout(Duration::from_millis(200), provider.fetch()).await;
+        assert!(fetched.is_err(), "connection must not be tunnelled");
+
+        let mut buffer = [0u8; 1];
+        assert!(!matches!(client.read(&mut buffer).await, Ok(1)));
+    }
+
+    #[tokio::test]
+    async fn redirect_tunnels_to_the_original_destination() {
+        let (mut provider, address) = listen(TransparentMode::Redirect).await;
+        provider.original_dst = |_, _| Ok(SocketAddr::from(([192, 0, 2, 1], 443)));
+
+        let destination = destination(&mut provider, address).await;
+        assert_eq!(destination, SocketAddr::from(([192, 0, 2, 1], 443)));
+    }
+
+    #[tokio::test]
+    async fn redirect_drops_connections_to_the_listener_itself() {
+        let (mut provider, address) = listen(TransparentMode::Redirect).await;
+        provider.original_dst = |_, local| Ok(local);
+        let _client = TcpStream::connect(address).await.unwrap();
+
+        let fetched = tokio::time::timeout(Duration::from_millis(200), provider.fetch()).await;
+        assert!(fetched.is_err(), "connection must not be tunnelled");
+    }
+
+    #[tokio::test]
+    async fn tproxy_tunnels_to_the_local_address() {
+        let (mut provider, address) = listen(TransparentMode::TProxy).await;
+
+        assert_eq!(destination(&mut provider, address).await, address);
+    }
+}
@@ -1,14 +1,22 @@
 use std::future::Future;
 use std::io::Result;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use bytes::Bytes;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::auth::Hello;
+use crate::response::Response;
+
+pub mod auth;
 pub mod client;
+pub mod inbound;
 pub mod request;
+pub mod resolver;
 pub mod response;
 pub mod server;
+pub mod transport;
 
 pub trait Streamable: Sized + Send + Sync {
     fn read<T>(stream: &mut T) -> impl Future<Output = Result<Self>> + Send
@@ -24,10 +32,44 @@ pub trait ToBytes {
     fn to_bytes(self) -> Bytes;
 }
 
+/// Yields the streams a `Client` or `Server` runs on, `None` once exhausted.
+///
+/// Accept providers with a handshake, such as TLS, fetch from the provider
+/// they wrap while earlier handshakes are still running, so a wrapped
+/// provider's `fetch` must be cancel safe.
 pub trait Provider<T> {
     fn fetch(&mut self) -> impl Future<Output = Option<T>> + Send;
 }
 
+/// Surfaces the tunnel [`Response`] to the local side of a `Client`, e.g. as a
+/// SOCKS5 reply. Called once per response, before any payload is relayed.
+pub trait Reply: Send {
+    fn reply(&mut self, response: &Response) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Looks up the addresses of a domain for a `Server`, in order of preference.
+/// A successful lookup yields at least one address.
 pub trait Resolver: Send + Sync {
-    fn lookup(&self, domain: &str, port: u16) -> impl Future<Output = Result<SocketAddr>> + Send;
+    fn lookup(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<Vec<SocketAddr>>> + Send;
+
+    /// Like [`lookup`], along with how long the addresses may be cached if
+    /// the resolver knows.
+    ///
+    /// [`lookup`]: Self::lookup
+    fn lookup_with_ttl(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<(Vec<SocketAddr>, Option<Duration>)>> + Send {
+        async move { Ok((self.lookup(domain, port).await?, None)) }
+    }
+}
+
+/// Decides whether the sender of a [`Hello`] may open tunnels on a `Server`.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, hello: &Hello) -> Result<()>;
 }
@@ -1,5 +1,6 @@
-use std::io::{Error, Result};
+use std::io::{Error, ErrorKind, Result};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -9,21 +10,106 @@ use crate::{Resolver, Streamable, ToBytes};
 // ===== Request =====
 #[rustfmt::skip]
 mod consts_request_type {
-    pub const REQUEST_TYPE_TCP_CONNECT: u8 = 0x01;
+    pub const REQUEST_TYPE_TCP_CONNECT:   u8 = 0x01;
+    pub const REQUEST_TYPE_UDP_ASSOCIATE: u8 = 0x02;
+    pub const REQUEST_TYPE_TCP_BIND:      u8 = 0x03;
+
+    pub const FLAG_EXTENSIONS:            u8 = 0x80;
 }
 
 /// ## Bytes
 /// ```text
-///          +------+------+----------+------+
-///          | RTYP | ATYP |   ADDR   | PORT |
-///          +------+------+----------+------+
-///          |  1   |  1   | Variable |  2   |
-///          +------+------+----------+------+
+///          +------+------+----------+------+------------+
+///          | RTYP | ATYP |   ADDR   | PORT | EXTENSIONS |
+///          +------+------+----------+------+------------+
+///          |  1   |  1   | Variable |  2   |  Variable  |
+///          +------+------+----------+------+------------+
 /// ```
 ///
+/// [`Extensions`] follow when bit `0x80` of `RTYP` is set. A request without
+/// extensions is encoded exactly as before extensions existed, so it is still
+/// understood by servers that do not know them.
+///
+/// `UDPAssociate` carries no address, only the `RTYP` byte is sent. After a
+/// succeed response the stream carries [`Datagram`]s in both directions.
+///
+/// `TCPBind` asks the server to listen on `ADDR`. The server answers twice,
+/// first with the bound address, then with the peer address of the accepted
+/// connection, which is spliced to the stream afterwards.
+///
 #[derive(Debug, Clone)]
 pub enum Request {
     TCPConnect(Address),
+    UDPAssociate,
+    TCPBind(Address),
+}
+
+impl Request {
+    /// Writes the request followed by `extensions`, empty extensions are not
+    /// written at all.
+    pub async fn write_with_extensions<T>(
+        self,
+        extensions: Extensions,
+        stream: &mut T,
+    ) -> Result<()>
+    where
+        T: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        if extensions.is_empty() {
+            return self.write(stream).await;
+        }
+
+        let extensions = extensions.encode()?;
+        let mut bytes = BytesMut::from(self.to_bytes());
+        bytes[0] |= consts_request_type::FLAG_EXTENSIONS;
+        bytes.extend(extensions);
+
+        stream.write_all(&bytes).await
+    }
+
+    pub async fn read_with_extensions<T>(stream: &mut T) -> Result<(Self, Extensions)>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        let request_type = stream.read_u8().await?;
+
+        Self::read_after_type(request_type, stream).await
+    }
+
+    /// Reads the rest of a request whose `RTYP` byte was already read, e.g.
+    /// to tell it apart from a [`Hello`].
+    ///
+    /// [`Hello`]: crate::auth::Hello
+    pub async fn read_after_type<T>(request_type: u8, stream: &mut T) -> Result<(Self, Extensions)>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        let request = match request_type & !consts_request_type::FLAG_EXTENSIONS {
+            consts_request_type::REQUEST_TYPE_TCP_CONNECT => {
+                Request::TCPConnect(Address::read(stream).await?)
+            }
+
+            consts_request_type::REQUEST_TYPE_UDP_ASSOCIATE => Request::UDPAssociate,
+
+            consts_request_type::REQUEST_TYPE_TCP_BIND => {
+                Request::TCPBind(Address::read(stream).await?)
+            }
+
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("unsupported request type {}", request_type),
+                ))
+            }
+        };
+
+        let extensions = match request_type & consts_request_type::FLAG_EXTENSIONS != 0 {
+            true => Extensions::read(stream).await?,
+            false => Extensions::new(),
+        };
+
+        Ok((request, extensions))
+    }
 }
 
 impl ToBytes for Request {
@@ -35,6 +121,13 @@ impl ToBytes for Request {
                 bytes.put_u8(consts_request_type::REQUEST_TYPE_TCP_CONNECT);
                 bytes.extend(value.to_bytes());
             }
+            Self::UDPAssociate => {
+                bytes.put_u8(consts_request_type::REQUEST_TYPE_UDP_ASSOCIATE);
+            }
+            Self::TCPBind(value) => {
+                bytes.put_u8(consts_request_type::REQUEST_TYPE_TCP_BIND);
+                bytes.extend(value.to_bytes());
+            }
         };
 
         bytes.freeze()
@@ -49,26 +142,178 @@ impl Streamable for Request {
         stream.write_all(&self.to_bytes()).await
     }
 
+    /// Extensions are read and dropped, see [`Request::read_with_extensions`].
     async fn read<T>(stream: &mut T) -> Result<Self>
     where
         T: AsyncReadExt + Unpin + Send + 'static,
     {
-        let request_type = stream.read_u8().await?;
+        let (request, _) = Self::read_with_extensions(stream).await?;
 
-        let request = match request_type {
-            consts_request_type::REQUEST_TYPE_TCP_CONNECT => {
-                Request::TCPConnect(Address::read(stream).await?)
-            }
+        Ok(request)
+    }
+}
 
-            _ => {
+// ===== Extensions =====
+#[rustfmt::skip]
+mod consts_extension_type {
+    pub const USER_ID:    u8 = 0x01;
+    pub const TIMEOUT:    u8 = 0x02;
+    pub const FLOW_LABEL: u8 = 0x03;
+    pub const EARLY_DATA: u8 = 0x04;
+}
+
+/// Optional type-length-value entries carried by a [`Request`].
+///
+/// ## Bytes
+/// ```text
+///          +------+------+------+----------+-----+
+///          | ELEN | TYPE | VLEN |  VALUE   | ... |
+///          +------+------+------+----------+-----+
+///          |  2   |  1   |  2   | Variable |     |
+///          +------+------+------+----------+-----+
+/// ```
+///
+/// `ELEN` is the size of all entries following it. Entries of a type the
+/// reader does not know are kept as they are and otherwise ignored.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extensions {
+    entries: Vec<(u8, Bytes)>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Sets the entry of type `kind`, replacing a previous one.
+    pub fn with(mut self, kind: u8, value: Bytes) -> Self {
+        self.entries.retain(|(entry, _)| *entry != kind);
+        self.entries.push((kind, value));
+        self
+    }
+
+    pub fn get(&self, kind: u8) -> Option<&Bytes> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| *entry == kind)
+            .map(|(_, value)| value)
+    }
+
+    /// Identifies the user a request is made for, e.g. for server logs.
+    pub fn with_user_id(self, user_id: &str) -> Self {
+        let value = Bytes::copy_from_slice(user_id.as_bytes());
+        self.with(consts_extension_type::USER_ID, value)
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        let value = self.get(consts_extension_type::USER_ID)?;
+        std::str::from_utf8(value).ok()
+    }
+
+    /// How long the server should try to reach the target, in milliseconds
+    /// on the wire.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        let millis = timeout.as_millis().min(u32::MAX as u128) as u32;
+        self.with(
+            consts_extension_type::TIMEOUT,
+            Bytes::copy_from_slice(&millis.to_be_bytes()),
+        )
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        let value = self.get(consts_extension_type::TIMEOUT)?;
+        let millis = u32::from_be_bytes(value.as_ref().try_into().ok()?);
+        Some(Duration::from_millis(millis as u64))
+    }
+
+    /// Groups requests belonging together, opaque to the server.
+    pub fn with_flow_label(self, flow_label: u32) -> Self {
+        self.with(
+            consts_extension_type::FLOW_LABEL,
+            Bytes::copy_from_slice(&flow_label.to_be_bytes()),
+        )
+    }
+
+    pub fn flow_label(&self) -> Option<u32> {
+        let value = self.get(consts_extension_type::FLOW_LABEL)?;
+        Some(u32::from_be_bytes(value.as_ref().try_into().ok()?))
+    }
+
+    /// First payload bytes of a `TCPConnect`, written to the target by the
+    /// server as soon as it is connected.
+    pub fn with_early_data(self, early_data: Bytes) -> Self {
+        self.with(consts_extension_type::EARLY_DATA, early_data)
+    }
+
+    pub fn early_data(&self) -> Option<&Bytes> {
+        self.get(consts_extension_type::EARLY_DATA)
+    }
+
+    fn encode(&self) -> Result<Bytes> {
+        let mut entries = BytesMut::new();
+
+        for (kind, value) in &self.entries {
+            if value.len() > u16::MAX as usize {
                 return Err(Error::other(format!(
-                    "unsupported request type {}",
-                    request_type
-                )))
+                    "request extension {} too large {}",
+                    kind,
+                    value.len()
+                )));
             }
-        };
 
-        Ok(request)
+            entries.put_u8(*kind);
+            entries.put_u16(value.len() as u16);
+            entries.extend_from_slice(value);
+        }
+
+        if entries.len() > u16::MAX as usize {
+            return Err(Error::other(format!(
+                "request extensions too large {}",
+                entries.len()
+            )));
+        }
+
+        let mut bytes = BytesMut::with_capacity(2 + entries.len());
+        bytes.put_u16(entries.len() as u16);
+        bytes.extend(entries);
+
+        Ok(bytes.freeze())
+    }
+
+    async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send,
+    {
+        let extensions_len = stream.read_u16().await? as usize;
+        let mut buffer = vec![0u8; extensions_len];
+        stream.read_exact(&mut buffer).await?;
+
+        let mut buffer = Bytes::from(buffer);
+        let mut extensions = Self::new();
+
+        while !buffer.is_empty() {
+            if buffer.len() < 3 {
+                return Err(Error::other("truncated request extension"));
+            }
+
+            let kind = buffer[0];
+            let value_len = u16::from_be_bytes([buffer[1], buffer[2]]) as usize;
+            if buffer.len() < 3 + value_len {
+                return Err(Error::other("truncated request extension"));
+            }
+
+            let value = buffer.slice(3..3 + value_len);
+            buffer = buffer.slice(3 + value_len..);
+
+            extensions = extensions.with(kind, value);
+        }
+
+        Ok(extensions)
     }
 }
 
@@ -97,17 +342,51 @@ pub enum Address {
 }
 
 impl Address {
+    /// The most preferred address of [`to_socket_addresses`].
+    ///
+    /// [`to_socket_addresses`]: Self::to_socket_addresses
     pub async fn to_socket_address<R>(self, resolver: &R) -> Result<SocketAddr>
     where
         R: Resolver,
     {
-        let socket_address = match self {
-            Self::Domain(domain, port) => resolver.lookup(&domain, port).await?.into(),
-            Self::IPv4(addr) => addr.into(),
-            Self::IPv6(addr) => addr.into(),
+        let socket_addresses = self.to_socket_addresses(resolver).await?;
+        Ok(socket_addresses[0])
+    }
+
+    /// Every address a domain resolves to in order of preference, never
+    /// empty.
+    pub async fn to_socket_addresses<R>(self, resolver: &R) -> Result<Vec<SocketAddr>>
+    where
+        R: Resolver,
+    {
+        let socket_addresses = match self {
+            Self::Domain(domain, port) => {
+                let socket_addresses = resolver.lookup(&domain, port).await?;
+                if socket_addresses.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("no address for {}", domain),
+                    ));
+                }
+                socket_addresses
+            }
+            Self::IPv4(addr) => vec![addr.into()],
+            Self::IPv6(addr) => vec![addr.into()],
         };
 
-        Ok(socket_address)
+        Ok(socket_addresses)
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(value: SocketAddr) -> Self {
+        match value {
+            SocketAddr::V4(addr) => Self::IPv4(addr),
+            SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+                Some(ip) => Self::IPv4(SocketAddrV4::new(ip, addr.port())),
+                None => Self::IPv6(addr),
+            },
+        }
     }
 }
 
@@ -210,3 +489,169 @@ impl Streamable for Address {
         Ok(result)
     }
 }
+
+// ===== Datagram =====
+/// ## Bytes
+/// ```text
+///          +------+----------+------+------+----------+
+///          | ATYP |   ADDR   | PORT | DLEN |   DATA   |
+///          +------+----------+------+------+----------+
+///          |  1   | Variable |  2   |  2   | Variable |
+///          +------+----------+------+------+----------+
+/// ```
+///
+/// `ADDR` is the destination when sent by the client, and the source when
+/// sent by the server.
+///
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub address: Address,
+    pub payload: Bytes,
+}
+
+impl Datagram {
+    pub fn new(address: Address, payload: Bytes) -> Self {
+        Self { address, payload }
+    }
+}
+
+impl ToBytes for Datagram {
+    fn to_bytes(self) -> Bytes {
+        let mut bytes = BytesMut::new();
+
+        bytes.extend(self.address.to_bytes());
+        bytes.put_u16(self.payload.len() as u16);
+        bytes.extend_from_slice(&self.payload);
+
+        bytes.freeze()
+    }
+}
+
+impl Streamable for Datagram {
+    async fn write<T>(self, stream: &mut T) -> Result<()>
+    where
+        T: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        if self.payload.len() > u16::MAX as usize {
+            return Err(Error::other(format!(
+                "datagram payload too large {}",
+                self.payload.len()
+            )));
+        }
+
+        stream.write_all(&self.to_bytes()).await
+    }
+
+    async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        let address = Address::read(stream).await?;
+
+        let payload_len = stream.read_u16().await? as usize;
+        let mut payload = vec![0u8; payload_len];
+        stream.read_exact(&mut payload).await?;
+
+        Ok(Datagram::new(address, payload.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    async fn encode(request: Request, extensions: Extensions) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        request
+            .write_with_extensions(extensions, &mut bytes)
+            .await
+            .unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn empty_extensions_keep_the_original_encoding() {
+        let ipv4 = Address::IPv4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 443));
+        let domain = Address::Domain("example.com".to_string(), 80);
+
+        #[rustfmt::skip]
+        let cases = [
+            (Request::TCPConnect(ipv4.clone()), vec![0x01, 0x02, 192, 0, 2, 1, 0x01, 0xBB]),
+            (Request::TCPConnect(domain), [&[0x01, 0x01, 11][..], b"example.com", &[0x00, 0x50]].concat()),
+            (Request::UDPAssociate, vec![0x02]),
+            (Request::TCPBind(ipv4), vec![0x03, 0x02, 192, 0, 2, 1, 0x01, 0xBB]),
+        ];
+
+        for (request, expected) in cases {
+            let bytes = encode(request.clone(), Extensions::new()).await;
+            assert_eq!(bytes, expected);
+
+            let (read, extensions) = Request::read_with_extensions(&mut Cursor::new(bytes))
+                .await
+                .unwrap();
+            assert_eq!(read.to_bytes(), request.to_bytes());
+            assert!(extensions.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn extensions_read_back() {
+        let extensions = Extensions::new()
+            .with_user_id("alice")
+            .with_timeout(Duration::from_secs(3))
+            .with_flow_label(7);
+
+        let bytes = encode(Request::UDPAssociate, extensions.clone()).await;
+        assert_eq!(bytes[0], 0x82);
+
+        let (_, read) = Request::read_with_extensions(&mut Cursor::new(bytes))
+            .await
+            .unwrap();
+        assert_eq!(read, extensions);
+        assert_eq!(read.user_id(), Some("alice"));
+        assert_eq!(read.timeout(), Some(Duration::from_secs(3)));
+        assert_eq!(read.flow_label(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn unknown_extensions_are_skipped() {
+        #[rustfmt::skip]
+        let bytes = vec![
+            0x82,
+            0x00, 0x0C,
+            0x7F, 0x00, 0x02, 0xAA, 0xBB,
+            0x03, 0x00, 0x04, 0x00, 0x00, 0x00, 0x07,
+            b'x', b'y',
+        ];
+        let mut stream = Cursor::new(bytes);
+
+        let (_, extensions) = Request::read_with_extensions(&mut stream).await.unwrap();
+        assert_eq!(extensions.flow_label(), Some(7));
+        assert_eq!(extensions.user_id(), None);
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"xy");
+    }
+
+    #[tokio::test]
+    async fn truncated_extensions_are_rejected() {
+        #[rustfmt::skip]
+        let cases: [&[u8]; 3] = [
+            // The entry claims more value than the block holds.
+            &[0x82, 0x00, 0x05, 0x03, 0x00, 0x04, 0x00, 0x00],
+            // The block ends within an entry header.
+            &[0x82, 0x00, 0x02, 0x03, 0x00],
+            // The stream ends within the block.
+            &[0x82, 0x00, 0x07, 0x03, 0x00, 0x04, 0x00],
+        ];
+
+        for bytes in cases {
+            let read = Request::read_with_extensions(&mut Cursor::new(bytes.to_vec())).await;
+            assert!(read.is_err(), "{:?} was accepted", bytes);
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::Resolver;
+
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(10);
+const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+enum Outcome {
+    Found(Vec<IpAddr>),
+    NotFound(String),
+}
+
+/// What a lookup in flight hands to the lookups waiting on it, errors that
+/// are not cached included.
+type Shared = Option<std::result::Result<(Outcome, Duration), (ErrorKind, String)>>;
+
+struct Entry {
+    outcome: Outcome,
+    expires: Instant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// Entries by the tick of their last use, least recent first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    in_flight: HashMap<String, watch::Receiver<Shared>>,
+}
+
+impl State {
+    fn touch(&mut self, domain: &str) {
+        self.tick += 1;
+
+        if let Some(entry) = self.entries.get_mut(domain) {
+            self.recency.remove(&entry.tick);
+            entry.tick = self.tick;
+            self.recency.insert(self.tick, domain.to_string());
+        }
+    }
+
+    fn insert(&mut self, domain: String, outcome: Outcome, ttl: Duration, capacity: usize) {
+        self.tick += 1;
+
+        let entry = Entry {
+            outcome,
+            expires: Instant::now() + ttl,
+            tick: self.tick,
+        };
+        if let Some(previous) = self.entries.insert(domain.clone(), entry) {
+            self.recency.remove(&previous.tick);
+        }
+        self.recency.insert(self.tick, domain);
+
+        while self.entries.len() > capacity {
+            let Some((_, evicted)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&evicted);
+        }
+    }
+}
+
+/// Clears a lookup in flight however it ends, so that lookups waiting on a
+/// cancelled one start their own.
+struct InFlight<'a> {
+    state: &'a Mutex<State>,
+    domain: &'a str,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        lock(self.state).in_flight.remove(self.domain);
+    }
+}
+
+fn lock(state: &Mutex<State>) -> std::sync::MutexGuard<'_, State> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Caches the lookups of the wrapped resolver for as long as it says they
+/// are valid, or the configured TTL if it does not say. Domains that do not
+/// exist, failing with `NotFound`, are cached for the shorter negative TTL,
+/// other failures are not cached. Concurrent lookups of a domain share one
+/// lookup, and the least recently used domains are dropped beyond the
+/// configured capacity.
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<R> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            ttl: DEFAULT_TTL,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            capacity: DEFAULT_CAPACITY,
+            state: Mutex::new(State::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// How long addresses are cached when the wrapped resolver gives no TTL.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long a domain that does not exist is cached.
+    pub fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// Most domains cached at once.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Lookups answered without asking the wrapped resolver, including those
+    /// that joined a lookup in flight.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups passed on to the wrapped resolver.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl<R> CachingResolver<R>
+where
+    R: Resolver,
+{
+    async fn resolve(&self, domain: &str, port: u16) -> Result<(Outcome, Duration)> {
+        let domain = domain.to_ascii_lowercase();
+
+        loop {
+            let (mut receiver, sender) = {
+                let mut state = lock(&self.state);
+
+                let now = Instant::now();
+                let cached = state
+                    .entries
+                    .get(&domain)
+                    .filter(|entry| entry.expires > now)
+                    .map(|entry| (entry.outcome.clone(), entry.expires - now));
+
+                if let Some(cached) = cached {
+                    state.touch(&domain);
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(cached);
+                }
+
+                match state.in_flight.get(&domain) {
+                    Some(receiver) => (receiver.clone(), None),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        state.in_flight.insert(domain.clone(), receiver.clone());
+                        (receiver, Some(sender))
+                    }
+                }
+            };
+
+            if let Some(sender) = sender {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return self.resolve_uncached(domain, port, sender).await;
+            }
+
+            // A cancelled lookup drops its sender, start over then.
+            let Ok(shared) = receiver.wait_for(Option::is_some).await else {
+                continue;
+            };
+
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return match shared.clone().expect("waited for a result") {
+                Ok(resolved) => Ok(resolved),
+                Err((kind, message)) => Err(Error::new(kind, message)),
+            };
+        }
+    }
+
+    async fn resolve_uncached(
+        &self,
+        domain: String,
+        port: u16,
+        sender: watch::Sender<Shared>,
+    ) -> Result<(Outcome, Duration)> {
+        let _in_flight = InFlight {
+            state: &self.state,
+            domain: &domain,
+        };
+
+        let resolved = match self.inner.lookup_with_ttl(&domain, port).await {
+            Ok((addresses, ttl)) => Ok((
+                Outcome::Found(addresses.iter().map(SocketAddr::ip).collect()),
+                ttl.unwrap_or(self.ttl),
+            )),
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                Ok((Outcome::NotFound(error.to_string()), self.negative_ttl))
+            }
+            Err(error) => Err(error),
+        };
+
+        if let Ok((outcome, ttl)) = &resolved {
+            lock(&self.state).insert(domain.clone(), outcome.clone(), *ttl, self.capacity);
+        }
+
+        let shared = match &resolved {
+            Ok(resolved) => Ok(resolved.clone()),
+            Err(error) => Err((error.kind(), error.to_string())),
+        };
+        sender.send_replace(Some(shared));
+
+        resolved
+    }
+}
+
+impl<R> Resolver for CachingResolver<R>
+where
+    R: Resolver,
+{
+    async fn lookup(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let (addresses, _) = self.lookup_with_ttl(domain, port).await?;
+        Ok(addresses)
+    }
+
+    /// The TTL is what remains of the cached one.
+    async fn lookup_with_ttl(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> Result<(Vec<SocketAddr>, Option<Duration>)> {
+        match self.resolve(domain, port).await? {
+            (Outcome::Found(addresses), ttl) => {
+                let addresses = addresses
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, port))
+                    .collect();
+                Ok((addresses, Some(ttl)))
+            }
+            (Outcome::NotFound(message), _) => Err(Error::new(ErrorKind::NotFound, message)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    const LATENCY: Duration = Duration::from_secs(1);
+
+    /// Answers every domain with one address after [`LATENCY`], but for
+    /// `missing.test` that does not exist and `broken.test` that times out.
+    struct Upstream {
+        ttl: Option<Duration>,
+        lookups: AtomicUsize,
+    }
+
+    impl Resolver for Upstream {
+        async fn lookup(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+            let (addresses, _) = self.lookup_with_ttl(domain, port).await?;
+            Ok(addresses)
+        }
+
+        async fn lookup_with_ttl(
+            &self,
+            domain: &str,
+            port: u16,
+        ) -> Result<(Vec<SocketAddr>, Option<Duration>)> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(LATENCY).await;
+
+            match domain {
+                "missing.test" => Err(Error::new(ErrorKind::NotFound, "no such domain")),
+                "broken.test" => Err(Error::new(ErrorKind::TimedOut, "no answer")),
+                _ => Ok((vec![SocketAddr::from(([192, 0, 2, 1], port))], self.ttl)),
+            }
+        }
+    }
+
+    fn cache(ttl: Option<Duration>) -> CachingResolver<Upstream> {
+        CachingResolver::new(Upstream {
+            ttl,
+            lookups: AtomicUsize::new(0),
+        })
+    }
+
+    fn lookups(cache: &CachingResolver<Upstream>) -> usize {
+        cache.inner.lookups.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn addresses_are_cached_until_their_ttl_expires() {
+        let cache = cache(Some(Duration::from_secs(30)));
+
+        let (addresses, ttl) = cache.lookup_with_ttl("a.test", 443).await.unwrap();
+        assert_eq!(addresses, [SocketAddr::from(([192, 0, 2, 1], 443))]);
+        assert_eq!(ttl, Some(Duration::from_secs(30)));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let (addresses, ttl) = cache.lookup_with_ttl("A.test", 80).await.unwrap();
+        assert_eq!(addresses, [SocketAddr::from(([192, 0, 2, 1], 80))]);
+        assert_eq!(ttl, Some(Duration::from_secs(20)));
+        assert_eq!(lookups(&cache), 1);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        cache.lookup("a.test", 443).await.unwrap();
+        assert_eq!(lookups(&cache), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn configured_ttl_applies_without_one_from_upstream() {
+        let cache = cache(None).with_ttl(Duration::from_secs(5));
+
+        let (_, ttl) = cache.lookup_with_ttl("a.test", 443).await.unwrap();
+        assert_eq!(ttl, Some(Duration::from_secs(5)));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        cache.lookup("a.test", 443).await.unwrap();
+        assert_eq!(lookups(&cache), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_domains_are_cached_for_the_negative_ttl() {
+        let cache = cache(None).with_negative_ttl(Duration::from_secs(3));
+
+        for _ in 0..2 {
+            let error = cache.lookup("missing.test", 443).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::NotFound);
+        }
+        assert_eq!(lookups(&cache), 1);
+
+        tokio::time::advance(Duration::from_secs(3)).await;
+        cache.lookup("missing.test", 443).await.unwrap_err();
+        assert_eq!(lookups(&cache), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_failures_are_not_cached() {
+        let cache = cache(None);
+
+        for _ in 0..2 {
+            let error = cache.lookup("broken.test", 443).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::TimedOut);
+        }
+        assert_eq!(lookups(&cache), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_lookups_share_one_query() {
+        let cache = cache(None);
+
+        let (a, b, c) = tokio::join!(
+            cache.lookup("a.test", 443),
+            cache.lookup("a.test", 443),
+            cache.lookup("a.test", 80),
+        );
+        assert_eq!(a.unwrap(), b.unwrap());
+        assert_eq!(c.unwrap(), [SocketAddr::from(([192, 0, 2, 1], 80))]);
+        assert_eq!(lookups(&cache), 1);
+
+        let (a, b) = tokio::join!(
+            cache.lookup("broken.test", 443),
+            cache.lookup("broken.test", 443),
+        );
+        assert_eq!(a.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(b.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(lookups(&cache), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_lookups_take_over_a_cancelled_one() {
+        let cache = cache(None);
+
+        let (cancelled, waiting) = tokio::join!(
+            tokio::time::timeout(LATENCY / 2, cache.lookup("a.test", 443)),
+            cache.lookup("a.test", 443),
+        );
+        assert!(cancelled.is_err());
+        assert!(waiting.is_ok());
+        assert_eq!(lookups(&cache), 2);
+
+        cache.lookup("a.test", 443).await.unwrap();
+        assert_eq!(lookups(&cache), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn least_recently_used_domains_are_evicted() {
+        let cache = cache(None).with_capacity(2);
+
+        for domain in ["a.test", "b.test", "a.test", "c.test"] {
+            cache.lookup(domain, 443).await.unwrap();
+        }
+        assert_eq!(lookups(&cache), 3);
+
+        cache.lookup("a.test", 443).await.unwrap();
+        cache.lookup("c.test", 443).await.unwrap();
+        assert_eq!(lookups(&cache), 3);
+
+        cache.lookup("b.test", 443).await.unwrap();
+        assert_eq!(lookups(&cache), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hits_and_misses_are_counted() {
+        let cache = cache(None);
+
+        cache.lookup("a.test", 443).await.unwrap();
+        cache.lookup("a.test", 443).await.unwrap();
+        let _ = tokio::join!(cache.lookup("b.test", 443), cache.lookup("b.test", 443),);
+        cache.lookup("missing.test", 443).await.unwrap_err();
+        cache.lookup("missing.test", 443).await.unwrap_err();
+
+        assert_eq!(cache.misses(), 3);
+        assert_eq!(cache.hits(), 3);
+    }
+}
@@ -0,0 +1,204 @@
+use std::cmp::Reverse;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::Resolver;
+
+type Lookup<'a> =
+    Pin<Box<dyn Future<Output = Result<(Vec<SocketAddr>, Option<Duration>)>> + Send + 'a>>;
+
+/// A [`Resolver`] behind a pointer, whatever its type.
+trait BoxedResolver: Send + Sync {
+    fn lookup_boxed<'a>(&'a self, domain: &'a str, port: u16) -> Lookup<'a>;
+}
+
+impl<R> BoxedResolver for R
+where
+    R: Resolver,
+{
+    fn lookup_boxed<'a>(&'a self, domain: &'a str, port: u16) -> Lookup<'a> {
+        Box::pin(self.lookup_with_ttl(domain, port))
+    }
+}
+
+/// Combines resolvers of any type.
+///
+/// A domain routed to a resolver by its suffix is only looked up there,
+/// so internal names never reach public DNS. Longer suffixes take precedence,
+/// `corp` routes both `corp` and `db.corp`. Other domains go through the
+/// fallback resolvers in the order they were added, until one succeeds, or
+/// fail with the error of the last one.
+#[derive(Default)]
+pub struct ChainResolver {
+    /// Suffixes with their resolvers, longest first.
+    routes: Vec<(String, Box<dyn BoxedResolver>)>,
+    fallbacks: Vec<Box<dyn BoxedResolver>>,
+}
+
+impl ChainResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a fallback resolver, may be called repeatedly in order of
+    /// preference.
+    pub fn with_resolver<R>(mut self, resolver: R) -> Self
+    where
+        R: Resolver + 'static,
+    {
+        self.fallbacks.push(Box::new(resolver));
+        self
+    }
+
+    /// Routes `suffix` and its subdomains to `resolver`, replacing an earlier
+    /// route of the same suffix.
+    pub fn with_route<S, R>(mut self, suffix: S, resolver: R) -> Self
+    where
+        S: AsRef<str>,
+        R: Resolver + 'static,
+    {
+        let suffix = suffix
+            .as_ref()
+            .trim_start_matches("*.")
+            .trim_matches('.')
+            .to_ascii_lowercase();
+
+        self.routes.retain(|(known, _)| *known != suffix);
+        self.routes.push((suffix, Box::new(resolver)));
+        self.routes.sort_by_key(|(suffix, _)| Reverse(suffix.len()));
+        self
+    }
+
+    fn route(&self, domain: &str) -> Option<&dyn BoxedResolver> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+
+        self.routes
+            .iter()
+            .find(|(suffix, _)| {
+                domain
+                    .strip_suffix(suffix.as_str())
+                    .is_some_and(|subdomain| subdomain.is_empty() || subdomain.ends_with('.'))
+            })
+            .map(|(_, resolver)| resolver.as_ref())
+    }
+}
+
+impl Resolver for ChainResolver {
+    async fn lookup(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let (addresses, _) = self.lookup_with_ttl(domain, port).await?;
+        Ok(addresses)
+    }
+
+    async fn lookup_with_ttl(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> Result<(Vec<SocketAddr>, Option<Duration>)> {
+        if let Some(resolver) = self.route(domain) {
+            return resolver.lookup_boxed(domain, port).await;
+        }
+
+        let mut last_error = None;
+
+        for resolver in &self.fallbacks {
+            match resolver.lookup_boxed(domain, port).await {
+                Ok(resolved) => return Ok(resolved),
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| Error::new(ErrorKind::NotConnected, "no resolver configured")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::resolver::hosts::HostsResolver;
+
+    /// Fails every lookup with `kind`.
+    struct Failing(ErrorKind);
+
+    impl Resolver for Failing {
+        async fn lookup(&self, _: &str, _: u16) -> Result<Vec<SocketAddr>> {
+            Err(Error::new(self.0, "failing"))
+        }
+    }
+
+    fn hosts(domain: &str, address: [u8; 4]) -> HostsResolver {
+        HostsResolver::new().with_host(domain, IpAddr::from(address))
+    }
+
+    async fn lookup(resolver: &ChainResolver, domain: &str) -> Result<IpAddr> {
+        let addresses = resolver.lookup(domain, 443).await?;
+        Ok(addresses[0].ip())
+    }
+
+    #[tokio::test]
+    async fn longest_route_wins() {
+        let resolver = ChainResolver::new()
+            .with_route("corp", hosts("*.corp", [10, 0, 0, 1]))
+            .with_route("*.eu.corp.", hosts("*.corp", [10, 0, 1, 1]))
+            .with_resolver(hosts("*.xcorp", [192, 0, 2, 1]));
+
+        assert_eq!(
+            lookup(&resolver, "db.corp").await.unwrap(),
+            IpAddr::from([10, 0, 0, 1])
+        );
+        assert_eq!(
+            lookup(&resolver, "DB.eu.corp").await.unwrap(),
+            IpAddr::from([10, 0, 1, 1])
+        );
+        assert_eq!(
+            lookup(&resolver, "db.xcorp").await.unwrap(),
+            IpAddr::from([192, 0, 2, 1])
+        );
+    }
+
+    #[tokio::test]
+    async fn routed_domains_do_not_fall_back() {
+        let resolver = ChainResolver::new()
+            .with_route("corp", HostsResolver::new())
+            .with_resolver(hosts("*.corp", [192, 0, 2, 1]));
+
+        let error = lookup(&resolver, "db.corp").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn fallbacks_are_tried_in_order() {
+        let resolver = ChainResolver::new()
+            .with_resolver(hosts("a.test", [192, 0, 2, 1]))
+            .with_resolver(Failing(ErrorKind::TimedOut))
+            .with_resolver(hosts("b.test", [192, 0, 2, 2]))
+            .with_resolver(hosts("b.test", [192, 0, 2, 3]));
+
+        assert_eq!(
+            lookup(&resolver, "a.test").await.unwrap(),
+            IpAddr::from([192, 0, 2, 1])
+        );
+        assert_eq!(
+            lookup(&resolver, "b.test").await.unwrap(),
+            IpAddr::from([192, 0, 2, 2])
+        );
+    }
+
+    #[tokio::test]
+    async fn last_error_is_returned_when_every_resolver_fails() {
+        let resolver = ChainResolver::new()
+            .with_resolver(Failing(ErrorKind::NotFound))
+            .with_resolver(Failing(ErrorKind::TimedOut));
+
+        let error = lookup(&resolver, "a.test").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        let error = lookup(&ChainResolver::new(), "a.test").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+    }
+}
@@ -0,0 +1,967 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::pki_types::ServerName;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::ClientConfig;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+
+use super::system::AddressFamily;
+use crate::Resolver;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_ATTEMPTS: usize = 2;
+
+#[cfg(feature = "tls")]
+const MAX_HTTP_RESPONSE_SIZE: usize = 64 * 1024;
+
+// ===== Message =====
+#[rustfmt::skip]
+mod consts_dns {
+    pub const HEADER_SIZE:    usize = 12;
+    pub const MAX_NAME_SIZE:  usize = 253;
+    pub const MAX_LABEL_SIZE: usize = 63;
+
+    pub const FLAG_QR:        u16 = 0x8000;
+    pub const FLAG_TC:        u16 = 0x0200;
+    pub const FLAG_RD:        u16 = 0x0100;
+    pub const RCODE_MASK:     u16 = 0x000F;
+
+    pub const RCODE_NOERROR:  u16 = 0;
+    pub const RCODE_NXDOMAIN: u16 = 3;
+
+    pub const TYPE_A:         u16 = 1;
+    pub const TYPE_AAAA:      u16 = 28;
+    pub const CLASS_IN:       u16 = 1;
+}
+
+/// A question for the `A` or `AAAA` records of a domain.
+///
+/// ## Bytes
+/// ```text
+///          +----+-------+---------+---------+---------+---------+
+///          | ID | FLAGS | QDCOUNT | ANCOUNT | NSCOUNT | ARCOUNT |
+///          +----+-------+---------+---------+---------+---------+
+///          | 2  |   2   |    2    |    2    |    2    |    2    |
+///          +----+-------+---------+---------+---------+---------+
+///          +----------+-------+--------+
+///          |  QNAME   | QTYPE | QCLASS |
+///          +----------+-------+--------+
+///          | Variable |   2   |   2    |
+///          +----------+-------+--------+
+/// ```
+///
+/// `QNAME` is the domain as length-prefixed labels ending with an empty one,
+/// see [RFC 1035].
+///
+/// [RFC 1035]: https://www.rfc-editor.org/rfc/rfc1035#section-4
+#[derive(Debug, Clone)]
+struct Query {
+    id: u16,
+    domain: String,
+    record_type: u16,
+}
+
+impl Query {
+    fn new(domain: &str, record_type: u16) -> Result<Self> {
+        let mut id = [0u8; 2];
+        getrandom::fill(&mut id).map_err(Error::from)?;
+
+        Ok(Self {
+            id: u16::from_be_bytes(id),
+            domain: domain.trim_end_matches('.').to_string(),
+            record_type,
+        })
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid domain name {}", self.domain),
+            )
+        };
+
+        if self.domain.is_empty() || self.domain.len() > consts_dns::MAX_NAME_SIZE {
+            return Err(invalid());
+        }
+
+        let mut bytes = BytesMut::new();
+
+        bytes.put_u16(self.id);
+        bytes.put_u16(consts_dns::FLAG_RD);
+        bytes.put_u16(1);
+        bytes.put_u16(0);
+        bytes.put_u16(0);
+        bytes.put_u16(0);
+
+        for label in self.domain.split('.') {
+            if label.is_empty() || label.len() > consts_dns::MAX_LABEL_SIZE {
+                return Err(invalid());
+            }
+
+            bytes.put_u8(label.len() as u8);
+            bytes.extend_from_slice(label.as_bytes());
+        }
+        bytes.put_u8(0);
+
+        bytes.put_u16(self.record_type);
+        bytes.put_u16(consts_dns::CLASS_IN);
+
+        Ok(bytes.to_vec())
+    }
+}
+
+/// What an upstream answered to a [`Query`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Answer {
+    Records {
+        addresses: Vec<IpAddr>,
+        ttl: Option<Duration>,
+    },
+    NxDomain,
+    Truncated,
+}
+
+impl Answer {
+    /// Parses the response to `query`, skipping records of other types such
+    /// as the `CNAME`s leading to the addresses.
+    fn decode(query: &Query, message: &[u8]) -> Result<Self> {
+        let malformed = || Error::new(ErrorKind::InvalidData, "malformed dns response");
+
+        if message.len() < consts_dns::HEADER_SIZE {
+            return Err(malformed());
+        }
+
+        let mut header = &message[..consts_dns::HEADER_SIZE];
+        let id = header.get_u16();
+        let flags = header.get_u16();
+        let questions = header.get_u16();
+        let answers = header.get_u16();
+
+        if id != query.id || flags & consts_dns::FLAG_QR == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "dns response does not match the query",
+            ));
+        }
+
+        if flags & consts_dns::FLAG_TC != 0 {
+            return Ok(Self::Truncated);
+        }
+
+        match flags & consts_dns::RCODE_MASK {
+            consts_dns::RCODE_NOERROR => {}
+            consts_dns::RCODE_NXDOMAIN => return Ok(Self::NxDomain),
+            rcode => {
+                return Err(Error::other(format!(
+                    "dns server failed with rcode {}",
+                    rcode
+                )))
+            }
+        }
+
+        let mut offset = consts_dns::HEADER_SIZE;
+        for _ in 0..questions {
+            offset = skip_name(message, offset).ok_or_else(malformed)? + 4;
+        }
+
+        let mut addresses = Vec::new();
+        let mut ttl: Option<u32> = None;
+
+        for _ in 0..answers {
+            offset = skip_name(message, offset).ok_or_else(malformed)?;
+
+            let mut record = message.get(offset..offset + 10).ok_or_else(malformed)?;
+            let record_type = record.get_u16();
+            let class = record.get_u16();
+            let record_ttl = record.get_u32();
+            let data_len = record.get_u16() as usize;
+            offset += 10;
+
+            let data = message
+                .get(offset..offset + data_len)
+                .ok_or_else(malformed)?;
+            offset += data_len;
+
+            if class != consts_dns::CLASS_IN || record_type != query.record_type {
+                continue;
+            }
+
+            let address = match record_type {
+                consts_dns::TYPE_A => <[u8; 4]>::try_from(data).map(IpAddr::from),
+                _ => <[u8; 16]>::try_from(data).map(IpAddr::from),
+            }
+            .map_err(|_| malformed())?;
+
+            addresses.push(address);
+            ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        }
+
+        Ok(Self::Records {
+            addresses,
+            ttl: ttl.map(|ttl| Duration::from_secs(ttl as u64)),
+        })
+    }
+}
+
+/// Offset right after the possibly compressed name at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+
+        match len {
+            0 => return Some(offset + 1),
+            // A pointer ends the name.
+            len if len & 0xC0 == 0xC0 => return Some(offset + 2),
+            len if len > consts_dns::MAX_LABEL_SIZE => return None,
+            len => offset += 1 + len,
+        }
+    }
+}
+
+// ===== Upstream =====
+#[derive(Clone)]
+enum Transport {
+    Udp,
+    Tcp,
+    #[cfg(feature = "tls")]
+    Tls {
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    },
+    #[cfg(feature = "tls")]
+    Https {
+        server_name: ServerName<'static>,
+        path: String,
+        config: Arc<ClientConfig>,
+    },
+}
+
+/// A DNS server a [`DnsResolver`] sends its queries to.
+#[derive(Clone)]
+pub struct Upstream {
+    address: SocketAddr,
+    transport: Transport,
+}
+
+impl Upstream {
+    /// Plain DNS over UDP, falling back to TCP for truncated answers.
+    pub fn udp(address: SocketAddr) -> Self {
+        Self {
+            address,
+            transport: Transport::Udp,
+        }
+    }
+
+    /// Plain DNS over TCP.
+    pub fn tcp(address: SocketAddr) -> Self {
+        Self {
+            address,
+            transport: Transport::Tcp,
+        }
+    }
+
+    /// DNS over TLS ([RFC 7858]), the certificate verified for
+    /// `server_name`.
+    ///
+    /// [RFC 7858]: https://www.rfc-editor.org/rfc/rfc7858
+    #[cfg(feature = "tls")]
+    pub fn tls(address: SocketAddr, server_name: &str, config: Arc<ClientConfig>) -> Result<Self> {
+        Ok(Self {
+            address,
+            transport: Transport::Tls {
+                server_name: server_name_from(server_name)?,
+                config,
+            },
+        })
+    }
+
+    /// DNS over HTTPS ([RFC 8484]), a `POST` to `server_name` and `path`,
+    /// usually `/dns-query`, over HTTP/1.1.
+    ///
+    /// [RFC 8484]: https://www.rfc-editor.org/rfc/rfc8484
+    #[cfg(feature = "tls")]
+    pub fn https(
+        address: SocketAddr,
+        server_name: &str,
+        path: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        Ok(Self {
+            address,
+            transport: Transport::Https {
+                server_name: server_name_from(server_name)?,
+                path: path.to_string(),
+                config,
+            },
+        })
+    }
+
+    async fn exchange(&self, query: &Query) -> Result<Answer> {
+        match &self.transport {
+            Transport::Udp => {
+                let answer = self.exchange_udp(query).await?;
+                if answer != Answer::Truncated {
+                    return Ok(answer);
+                }
+
+                let stream = TcpStream::connect(self.address).await?;
+                exchange_stream(stream, query).await
+            }
+            Transport::Tcp => {
+                let stream = TcpStream::connect(self.address).await?;
+                exchange_stream(stream, query).await
+            }
+            #[cfg(feature = "tls")]
+            Transport::Tls {
+                server_name,
+                config,
+            } => {
+                let stream = TcpStream::connect(self.address).await?;
+                let stream = TlsConnector::from(config.clone())
+                    .connect(server_name.clone(), stream)
+                    .await?;
+                exchange_stream(stream, query).await
+            }
+            #[cfg(feature = "tls")]
+            Transport::Https {
+                server_name,
+                path,
+                config,
+            } => {
+                let stream = TcpStream::connect(self.address).await?;
+                let stream = TlsConnector::from(config.clone())
+                    .connect(server_name.clone(), stream)
+                    .await?;
+                exchange_https(stream, &server_name.to_str(), path, query).await
+            }
+        }
+    }
+
+    async fn exchange_udp(&self, query: &Query) -> Result<Answer> {
+        let local: SocketAddr = match self.address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(self.address).await?;
+        socket.send(&query.encode()?).await?;
+
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        loop {
+            let len = socket.recv(&mut buffer).await?;
+
+            // Stray and malformed datagrams are dropped, the caller's
+            // timeout ends the wait.
+            match Answer::decode(query, &buffer[..len]) {
+                Err(error) if error.kind() == ErrorKind::InvalidData => continue,
+                answer => return answer,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn server_name_from(server_name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(server_name)
+        .map(|server_name| server_name.to_owned())
+        .map_err(|error| Error::new(ErrorKind::InvalidInput, error))
+}
+
+/// Sends the query and reads its answer, each preceded by its length.
+async fn exchange_stream<S>(mut stream: S, query: &Query) -> Result<Answer>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let message = query.encode()?;
+
+    let mut bytes = BytesMut::with_capacity(2 + message.len());
+    bytes.put_u16(message.len() as u16);
+    bytes.extend_from_slice(&message);
+    stream.write_all(&bytes).await?;
+    stream.flush().await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).await?;
+
+    Answer::decode(query, &message)
+}
+
+/// Posts the query and reads the answer from the body of the response, the
+/// connection closed after it.
+#[cfg(feature = "tls")]
+async fn exchange_https<S>(mut stream: S, host: &str, path: &str, query: &Query) -> Result<Answer>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Responses to an ID of zero can be cached by HTTP caches.
+    let query = Query {
+        id: 0,
+        ..query.clone()
+    };
+    let message = query.encode()?;
+
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        message.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_HTTP_RESPONSE_SIZE as u64 + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() > MAX_HTTP_RESPONSE_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "dns-over-https response too large",
+        ));
+    }
+
+    let body = http_body(&response)?;
+    Answer::decode(&query, &body)
+}
+
+/// Body of a successful HTTP/1.1 response, unchunked if need be.
+#[cfg(feature = "tls")]
+fn http_body(response: &[u8]) -> Result<Vec<u8>> {
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed dns-over-https response");
+
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(malformed)?;
+    let head = std::str::from_utf8(&response[..head_end]).map_err(|_| malformed())?;
+    let mut body = &response[head_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or_else(malformed)?;
+    if status != "200" {
+        return Err(Error::other(format!(
+            "dns-over-https server answered {}",
+            status
+        )));
+    }
+
+    let mut chunked = false;
+    let mut content_length = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<usize>().map_err(|_| malformed())?);
+        }
+    }
+
+    if !chunked {
+        let len = content_length.unwrap_or(body.len());
+        return body.get(..len).map(<[u8]>::to_vec).ok_or_else(malformed);
+    }
+
+    let mut unchunked = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(malformed)?;
+        let size = std::str::from_utf8(&body[..line_end]).map_err(|_| malformed())?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| malformed())?;
+
+        if size == 0 {
+            return Ok(unchunked);
+        }
+
+        let chunk = body
+            .get(line_end + 2..line_end + 2 + size)
+            .ok_or_else(malformed)?;
+        unchunked.extend_from_slice(chunk);
+        body = body.get(line_end + 4 + size..).ok_or_else(malformed)?;
+    }
+}
+
+// ===== DnsResolver =====
+/// Resolves by querying DNS servers directly, independent of the system's
+/// resolver configuration. Each query is sent to the upstreams in the order
+/// they were added, moving on to the next one when an upstream fails or does
+/// not answer in time, for the configured number of rounds. A domain that
+/// does not exist fails with `NotFound` right away.
+///
+/// With [`AddressFamily::Any`], IPv6 addresses come first.
+pub struct DnsResolver {
+    upstreams: Vec<Upstream>,
+    timeout: Duration,
+    attempts: usize,
+    family: AddressFamily,
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsResolver {
+    pub fn new() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            attempts: DEFAULT_ATTEMPTS,
+            family: AddressFamily::default(),
+        }
+    }
+
+    /// Adds an upstream, may be called repeatedly in order of preference.
+    pub fn with_upstream(mut self, upstream: Upstream) -> Self {
+        self.upstreams.push(upstream);
+        self
+    }
+
+    /// How long an upstream gets to answer a query.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many times every upstream is tried.
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    pub fn with_family(mut self, family: AddressFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    async fn query(&self, domain: &str, record_type: u16) -> Result<Answer> {
+        let mut last_error = None;
+
+        for _ in 0..self.attempts {
+            for upstream in &self.upstreams {
+                let query = Query::new(domain, record_type)?;
+
+                match tokio::time::timeout(self.timeout, upstream.exchange(&query)).await {
+                    Ok(Ok(answer)) => return Ok(answer),
+                    Ok(Err(error)) if error.kind() == ErrorKind::InvalidInput => return Err(error),
+                    Ok(Err(error)) => last_error = Some(error),
+                    Err(_) => {
+                        last_error = Some(Error::new(
+                            ErrorKind::TimedOut,
+                            format!("dns query to {} timed out", upstream.address),
+                        ))
+                    }
+                }
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| Error::new(ErrorKind::NotConnected, "no dns upstream configured")))
+    }
+}
+
+impl Resolver for DnsResolver {
+    async fn lookup(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let (addresses, _) = self.lookup_with_ttl(domain, port).await?;
+        Ok(addresses)
+    }
+
+    async fn lookup_with_ttl(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> Result<(Vec<SocketAddr>, Option<Duration>)> {
+        if let Ok(address) = domain.parse::<IpAddr>() {
+            let addresses = self.family.apply(vec![SocketAddr::new(address, port)]);
+            if !addresses.is_empty() {
+                return Ok((addresses, None));
+            }
+        }
+
+        let (v6, v4) = match self.family {
+            AddressFamily::V4Only => (None, Some(self.query(domain, consts_dns::TYPE_A).await)),
+            AddressFamily::V6Only => (Some(self.query(domain, consts_dns::TYPE_AAAA).await), None),
+            _ => {
+                let (v6, v4) = tokio::join!(
+                    self.query(domain, consts_dns::TYPE_AAAA),
+                    self.query(domain, consts_dns::TYPE_A)
+                );
+                (Some(v6), Some(v4))
+            }
+        };
+
+        let mut addresses = Vec::new();
+        let mut ttl: Option<Duration> = None;
+        let mut error = None;
+
+        for answer in [v6, v4].into_iter().flatten() {
+            match answer {
+                Ok(Answer::Records {
+                    addresses: found,
+                    ttl: found_ttl,
+                }) => {
+                    addresses.extend(
+                        found
+                            .into_iter()
+                            .map(|address| SocketAddr::new(address, port)),
+                    );
+                    ttl = match (ttl, found_ttl) {
+                        (Some(ttl), Some(found_ttl)) => Some(ttl.min(found_ttl)),
+                        (ttl, found_ttl) => ttl.or(found_ttl),
+                    };
+                }
+                Ok(Answer::NxDomain) => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("{} does not exist", domain),
+                    ))
+                }
+                Ok(Answer::Truncated) => {
+                    error.get_or_insert(Error::new(ErrorKind::InvalidData, "truncated dns answer"));
+                }
+                Err(found_error) => {
+                    error.get_or_insert(found_error);
+                }
+            }
+        }
+
+        let addresses = match self.family {
+            AddressFamily::PreferV4 => self.family.apply(addresses),
+            _ => addresses,
+        };
+
+        if addresses.is_empty() {
+            return Err(error.unwrap_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("no address for {}", domain))
+            }));
+        }
+
+        Ok((addresses, ttl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const TYPE_CNAME: u16 = 5;
+
+    /// A response to `query` with `flags` added and the given answers, each
+    /// named with a pointer to the question.
+    fn response(query: &Query, flags: u16, answers: &[(u16, u32, &[u8])]) -> Vec<u8> {
+        let mut message = query.encode().unwrap();
+
+        let flags = consts_dns::FLAG_QR | consts_dns::FLAG_RD | flags;
+        message[2..4].copy_from_slice(&flags.to_be_bytes());
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+
+        for (record_type, ttl, data) in answers {
+            message.extend_from_slice(&[0xC0, consts_dns::HEADER_SIZE as u8]);
+            message.extend_from_slice(&record_type.to_be_bytes());
+            message.extend_from_slice(&consts_dns::CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&ttl.to_be_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+
+        message
+    }
+
+    fn query(domain: &str) -> Query {
+        Query::new(domain, consts_dns::TYPE_A).unwrap()
+    }
+
+    #[test]
+    fn query_rejects_invalid_names() {
+        for domain in ["", "bad..name", &"a".repeat(64), &"a.".repeat(128)] {
+            let error = query(domain).encode().unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{:?}", domain);
+        }
+    }
+
+    #[test]
+    fn decode_skips_to_the_addresses() {
+        let query = query("a.test");
+        let mut message = response(
+            &query,
+            0,
+            &[
+                (TYPE_CNAME, 30, &[1, b'b', 0xC0, 12]),
+                (consts_dns::TYPE_A, 300, &[10, 0, 0, 1]),
+                (consts_dns::TYPE_AAAA, 10, &[0; 16]),
+            ],
+        );
+
+        // One more record named in full rather than with a pointer.
+        message[7] += 1;
+        message.extend_from_slice(&[1, b'a', 4, b't', b'e', b's', b't', 0]);
+        message.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 100, 0, 4, 10, 0, 0, 2]);
+
+        let answer = Answer::decode(&query, &message).unwrap();
+        assert_eq!(
+            answer,
+            Answer::Records {
+                addresses: vec![[10, 0, 0, 1].into(), [10, 0, 0, 2].into()],
+                ttl: Some(Duration::from_secs(100)),
+            }
+        );
+    }
+
+    #[test]
+    fn decode_reads_the_outcome() {
+        let query = query("a.test");
+
+        let answer = Answer::decode(&query, &response(&query, consts_dns::FLAG_TC, &[]));
+        assert_eq!(answer.unwrap(), Answer::Truncated);
+
+        let answer = Answer::decode(&query, &response(&query, consts_dns::RCODE_NXDOMAIN, &[]));
+        assert_eq!(answer.unwrap(), Answer::NxDomain);
+
+        let error = Answer::decode(&query, &response(&query, 2, &[])).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+
+        let answer = Answer::decode(&query, &response(&query, 0, &[])).unwrap();
+        assert_eq!(
+            answer,
+            Answer::Records {
+                addresses: Vec::new(),
+                ttl: None
+            }
+        );
+    }
+
+    #[test]
+    fn decode_rejects_responses_to_other_queries() {
+        let query = query("a.test");
+        let message = response(&query, 0, &[(consts_dns::TYPE_A, 60, &[10, 0, 0, 1])]);
+
+        let mut other_id = message.clone();
+        other_id[0] ^= 0xFF;
+        let mut not_response = message.clone();
+        not_response[2] &= !0x80;
+
+        for message in [other_id, not_response] {
+            let error = Answer::decode(&query, &message).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_malformed_responses() {
+        let query = query("a.test");
+        let message = response(&query, 0, &[(consts_dns::TYPE_A, 60, &[10, 0, 0, 1])]);
+
+        let mut long_label = message.clone();
+        long_label[consts_dns::HEADER_SIZE] = 64;
+        let bad_address = response(&query, 0, &[(consts_dns::TYPE_A, 60, &[10, 0, 0])]);
+
+        for message in [
+            message[..consts_dns::HEADER_SIZE - 1].to_vec(),
+            message[..message.len() - 1].to_vec(),
+            message[..consts_dns::HEADER_SIZE + 3].to_vec(),
+            long_label,
+            bad_address,
+        ] {
+            let error = Answer::decode(&query, &message).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", message);
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn http_body_reads_sized_and_chunked_bodies() {
+        let body = http_body(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcdef").unwrap();
+        assert_eq!(body, b"abc");
+
+        let body = http_body(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nabc").unwrap();
+        assert_eq!(body, b"abc");
+
+        let body = http_body(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2;x=y\r\nab\r\nA\r\n0123456789\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(body, b"ab0123456789");
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn http_body_rejects_failures() {
+        let error = http_body(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+
+        for response in [
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n"[..],
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nabc",
+            b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\nabc",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nz\r\nab\r\n0\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nab\r\n",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n",
+        ] {
+            let error = http_body(response).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", response);
+        }
+    }
+
+    // ===== Stand-in server =====
+    /// Answers `message` as a server would, `big.test` only over TCP.
+    fn answer(message: &[u8], tcp: bool) -> Vec<u8> {
+        let mut labels = Vec::new();
+        let mut offset = consts_dns::HEADER_SIZE;
+        while message[offset] != 0 {
+            let len = message[offset] as usize;
+            labels.push(std::str::from_utf8(&message[offset + 1..offset + 1 + len]).unwrap());
+            offset += 1 + len;
+        }
+
+        let query = Query {
+            id: u16::from_be_bytes([message[0], message[1]]),
+            domain: labels.join("."),
+            record_type: u16::from_be_bytes([message[offset + 1], message[offset + 2]]),
+        };
+        let a = consts_dns::TYPE_A;
+        let aaaa = consts_dns::TYPE_AAAA;
+        let v6 = [0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+        match (query.domain.as_str(), query.record_type) {
+            ("a.test", record_type) if record_type == a => response(
+                &query,
+                0,
+                &[(a, 300, &[10, 0, 0, 1]), (a, 100, &[10, 0, 0, 2])],
+            ),
+            ("a.test", _) => response(&query, 0, &[(aaaa, 50, &v6)]),
+            ("big.test", _) if !tcp => response(&query, consts_dns::FLAG_TC, &[]),
+            ("big.test", record_type) if record_type == a => {
+                response(&query, 0, &[(a, 60, &[10, 9, 9, 9])])
+            }
+            ("nx.test", _) => response(&query, consts_dns::RCODE_NXDOMAIN, &[]),
+            ("fail.test", _) => response(&query, 2, &[]),
+            _ => response(&query, 0, &[]),
+        }
+    }
+
+    /// A server on a loopback port for both UDP and TCP. Every UDP answer is
+    /// preceded by a stray datagram.
+    async fn stand_in() -> SocketAddr {
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(address).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            loop {
+                let (len, peer) = udp.recv_from(&mut buffer).await.unwrap();
+                udp.send_to(b"stray", peer).await.unwrap();
+                udp.send_to(&answer(&buffer[..len], false), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                tokio::spawn(async move {
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut message = vec![0u8; len as usize];
+                        stream.read_exact(&mut message).await.unwrap();
+
+                        let message = answer(&message, true);
+                        stream.write_u16(message.len() as u16).await.unwrap();
+                        stream.write_all(&message).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn resolves_over_udp_and_tcp() {
+        let address = stand_in().await;
+
+        for upstream in [Upstream::udp(address), Upstream::tcp(address)] {
+            let resolver = DnsResolver::new().with_upstream(upstream);
+            let (addresses, ttl) = resolver.lookup_with_ttl("a.test", 443).await.unwrap();
+
+            let expected: Vec<SocketAddr> = vec![
+                "[fd00::1]:443".parse().unwrap(),
+                "10.0.0.1:443".parse().unwrap(),
+                "10.0.0.2:443".parse().unwrap(),
+            ];
+            assert_eq!(addresses, expected);
+            assert_eq!(ttl, Some(Duration::from_secs(50)));
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_answers_are_asked_again_over_tcp() {
+        let resolver = DnsResolver::new().with_upstream(Upstream::udp(stand_in().await));
+
+        let addresses = resolver.lookup("big.test", 80).await.unwrap();
+        assert_eq!(
+            addresses,
+            vec!["10.9.9.9:80".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_queries_move_on_to_the_next_upstream() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let resolver = DnsResolver::new()
+            .with_timeout(Duration::from_millis(100))
+            .with_upstream(Upstream::udp(silent.local_addr().unwrap()))
+            .with_upstream(Upstream::udp(stand_in().await));
+        assert_eq!(resolver.lookup("a.test", 80).await.unwrap().len(), 3);
+
+        let resolver = DnsResolver::new()
+            .with_timeout(Duration::from_millis(100))
+            .with_upstream(Upstream::udp(silent.local_addr().unwrap()));
+        let error = resolver.lookup("a.test", 80).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn missing_names_are_not_found() {
+        let resolver = DnsResolver::new().with_upstream(Upstream::udp(stand_in().await));
+
+        let error = resolver.lookup("nx.test", 80).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        let error = resolver.lookup("empty.test", 80).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        let error = resolver.lookup("fail.test", 80).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+    }
+}
@@ -0,0 +1,251 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use crate::Resolver;
+
+/// Resolves from a fixed table of domains, such as an `/etc/hosts` file.
+///
+/// A domain starting with `*.` matches any of its subdomains, `*.corp`
+/// matches `db.corp` and `eu.db.corp` but not `corp` itself. Domains listed
+/// as they are take precedence over wildcards, and longer wildcards over
+/// shorter ones. Domains not in the table fail with `NotFound`.
+#[derive(Debug, Clone, Default)]
+pub struct HostsResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    /// Suffixes of wildcard domains with their leading dot, longest first.
+    wildcards: Vec<(String, Vec<IpAddr>)>,
+}
+
+impl HostsResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an address for `domain`, may be called repeatedly. Addresses are
+    /// returned in the order they were added.
+    pub fn with_host<D: AsRef<str>>(mut self, domain: D, address: IpAddr) -> Self {
+        let domain = normalize(domain.as_ref());
+
+        match domain
+            .strip_prefix('*')
+            .filter(|suffix| suffix.starts_with('.'))
+        {
+            Some(suffix) => match self.wildcards.iter_mut().find(|(known, _)| known == suffix) {
+                Some((_, addresses)) => addresses.push(address),
+                None => {
+                    self.wildcards.push((suffix.to_string(), vec![address]));
+                    self.wildcards
+                        .sort_by_key(|(suffix, _)| Reverse(suffix.len()));
+                }
+            },
+            None => self.hosts.entry(domain).or_default().push(address),
+        }
+
+        self
+    }
+
+    /// A resolver for the domains of `map`.
+    pub fn from_map<D: AsRef<str>>(map: HashMap<D, Vec<IpAddr>>) -> Self {
+        let mut resolver = Self::new();
+
+        for (domain, addresses) in map {
+            for address in addresses {
+                resolver = resolver.with_host(&domain, address);
+            }
+        }
+
+        resolver
+    }
+
+    /// Parses a hosts file, an address followed by its domains on each line,
+    /// and `#` starting a comment. A line with an invalid address fails the
+    /// parse with `InvalidData`.
+    pub fn parse(hosts: &str) -> Result<Self> {
+        let mut resolver = Self::new();
+
+        for (number, line) in hosts.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+
+            let Some(address) = fields.next() else {
+                continue;
+            };
+            let address = address.parse::<IpAddr>().map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid address {} on line {}", address, number + 1),
+                )
+            })?;
+
+            for domain in fields {
+                resolver = resolver.with_host(domain, address);
+            }
+        }
+
+        Ok(resolver)
+    }
+
+    /// Reads and parses the hosts file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn find(&self, domain: &str) -> Option<&[IpAddr]> {
+        let domain = normalize(domain);
+
+        if let Some(addresses) = self.hosts.get(&domain) {
+            return Some(addresses);
+        }
+
+        self.wildcards
+            .iter()
+            .find(|(suffix, _)| {
+                domain
+                    .strip_suffix(suffix.as_str())
+                    .is_some_and(|subdomain| !subdomain.is_empty())
+            })
+            .map(|(_, addresses)| addresses.as_slice())
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Resolver for HostsResolver {
+    async fn lookup(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addresses = self
+            .find(domain)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no address for {}", domain)))?;
+
+        Ok(addresses
+            .iter()
+            .map(|address| SocketAddr::new(*address, port))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    async fn lookup(resolver: &HostsResolver, domain: &str) -> Result<Vec<IpAddr>> {
+        let addresses = resolver.lookup(domain, 443).await?;
+        Ok(addresses.iter().map(SocketAddr::ip).collect())
+    }
+
+    #[tokio::test]
+    async fn parse_reads_addresses_and_their_domains() {
+        let resolver = HostsResolver::parse(
+            "# comment\n\
+             \n\
+             127.0.0.1 localhost local.test # trailing comment\n\
+             ::1\tlocalhost ip6-localhost\n\
+             10.0.0.1 # no domain\n\
+             10.0.0.2 DB.test.\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            lookup(&resolver, "localhost").await.unwrap(),
+            [ip("127.0.0.1"), ip("::1")]
+        );
+        assert_eq!(
+            lookup(&resolver, "local.test").await.unwrap(),
+            [ip("127.0.0.1")]
+        );
+        assert_eq!(
+            lookup(&resolver, "ip6-localhost").await.unwrap(),
+            [ip("::1")]
+        );
+        assert_eq!(
+            lookup(&resolver, "db.test").await.unwrap(),
+            [ip("10.0.0.2")]
+        );
+
+        let error = lookup(&resolver, "comment").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_addresses() {
+        let error = HostsResolver::parse("127.0.0.1 localhost\nlocalhost 127.0.0.1\n").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn load_reads_a_file() {
+        let path = std::env::temp_dir().join(format!("quicos-hosts-{}", std::process::id()));
+        std::fs::write(&path, "192.0.2.1 a.test\n").unwrap();
+
+        let resolver = HostsResolver::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let resolver = resolver.unwrap();
+        assert_eq!(
+            lookup(&resolver, "a.test").await.unwrap(),
+            [ip("192.0.2.1")]
+        );
+
+        let error = HostsResolver::load(&path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn wildcards_match_subdomains_only() {
+        let resolver = HostsResolver::new()
+            .with_host("*.corp", ip("10.0.0.1"))
+            .with_host("*.eu.corp", ip("10.0.1.1"))
+            .with_host("db.eu.corp", ip("10.0.1.2"));
+
+        assert_eq!(
+            lookup(&resolver, "db.corp").await.unwrap(),
+            [ip("10.0.0.1")]
+        );
+        assert_eq!(
+            lookup(&resolver, "a.b.corp").await.unwrap(),
+            [ip("10.0.0.1")]
+        );
+        assert_eq!(
+            lookup(&resolver, "web.eu.corp").await.unwrap(),
+            [ip("10.0.1.1")]
+        );
+        assert_eq!(
+            lookup(&resolver, "DB.eu.corp").await.unwrap(),
+            [ip("10.0.1.2")]
+        );
+
+        for domain in ["corp", "xcorp", "db.corporate"] {
+            let error = lookup(&resolver, domain).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::NotFound, "{}", domain);
+        }
+    }
+
+    #[tokio::test]
+    async fn from_map_adds_every_address() {
+        let resolver = HostsResolver::from_map(HashMap::from([
+            ("a.test", vec![ip("192.0.2.1"), ip("2001:db8::1")]),
+            ("*.b.test", vec![ip("192.0.2.2")]),
+        ]));
+
+        assert_eq!(
+            lookup(&resolver, "a.test").await.unwrap(),
+            [ip("192.0.2.1"), ip("2001:db8::1")]
+        );
+        assert_eq!(
+            lookup(&resolver, "c.b.test").await.unwrap(),
+            [ip("192.0.2.2")]
+        );
+
+        let addresses = resolver.lookup("a.test", 8080).await.unwrap();
+        assert!(addresses.iter().all(|address| address.port() == 8080));
+    }
+}
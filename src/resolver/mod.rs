@@ -0,0 +1,5 @@
+pub mod cache;
+pub mod chain;
+pub mod dns;
+pub mod hosts;
+pub mod system;
@@ -0,0 +1,113 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+
+use crate::Resolver;
+
+/// `getaddrinfo` messages of a name that does not exist, as worded by glibc,
+/// musl and the BSDs.
+const NOT_FOUND_MESSAGES: [&str; 4] = [
+    "Name or service not known",
+    "No address associated with hostname",
+    "Name does not resolve",
+    "nodename nor servname provided",
+];
+
+/// Which address families a lookup yields, and in which order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Both families, in the order the system returns them.
+    #[default]
+    Any,
+    V4Only,
+    V6Only,
+    /// Both families, IPv4 addresses first.
+    PreferV4,
+    /// Both families, IPv6 addresses first.
+    PreferV6,
+}
+
+impl AddressFamily {
+    /// Filters and orders `addresses`, keeping the system order within a
+    /// family.
+    pub fn apply(self, mut addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            Self::Any => {}
+            Self::V4Only => addresses.retain(SocketAddr::is_ipv4),
+            Self::V6Only => addresses.retain(SocketAddr::is_ipv6),
+            Self::PreferV4 => addresses.sort_by_key(SocketAddr::is_ipv6),
+            Self::PreferV6 => addresses.sort_by_key(SocketAddr::is_ipv4),
+        }
+
+        addresses
+    }
+}
+
+/// Resolves through the system resolver, `getaddrinfo` run on a blocking
+/// thread. Used by a `Server` unless another resolver is configured. Names
+/// that do not exist fail with `NotFound`.
+#[derive(Debug, Clone, Default)]
+pub struct SystemResolver {
+    family: AddressFamily,
+}
+
+impl SystemResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_family(mut self, family: AddressFamily) -> Self {
+        self.family = family;
+        self
+    }
+}
+
+impl Resolver for SystemResolver {
+    async fn lookup(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addresses = tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(classify)?
+            .collect();
+        let addresses = self.family.apply(addresses);
+
+        if addresses.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no address for {}", domain),
+            ));
+        }
+
+        Ok(addresses)
+    }
+}
+
+/// Lookup errors only carry the `getaddrinfo` message, tell a name that does
+/// not exist apart from a failure to resolve it.
+fn classify(error: Error) -> Error {
+    let message = error.to_string();
+
+    match NOT_FOUND_MESSAGES
+        .iter()
+        .any(|known| message.contains(known))
+    {
+        true => Error::new(ErrorKind::NotFound, message),
+        false => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_names_are_not_found() {
+        for message in NOT_FOUND_MESSAGES {
+            let error = Error::other(format!("failed to lookup address information: {}", message));
+            assert_eq!(classify(error).kind(), ErrorKind::NotFound, "{}", message);
+        }
+
+        let error = Error::other(
+            "failed to lookup address information: Temporary failure in name resolution",
+        );
+        assert_eq!(classify(error).kind(), ErrorKind::Other);
+    }
+}
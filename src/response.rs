@@ -1,31 +1,133 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::request::Address;
 use crate::{Streamable, ToBytes};
 
+// ===== Response =====
 #[rustfmt::skip]
-mod consts_response_type {
-    pub const SUCCEED: u8 = 0x01;
-    pub const NO_ACCEPTABLE_REQUEST: u8 = 0xFF;
+mod consts_response_flag {
+    pub const ADDRESS:    u8 = 0b0000_0001;
+    pub const REASON:     u8 = 0b0000_0010;
+    pub const EARLY_DATA: u8 = 0b0000_0100;
 }
 
-pub enum Response {
-    Succeed,
-    NoAcceptableMethod,
+/// ## Bytes
+/// ```text
+///          +------+-------+------+----------+------+------+----------+
+///          | CODE | FLAGS | ATYP |   ADDR   | PORT | RLEN |  REASON  |
+///          +------+-------+------+----------+------+------+----------+
+///          |  1   |   1   |  1   | Variable |  2   |  1   | Variable |
+///          +------+-------+------+----------+------+------+----------+
+/// ```
+///
+/// `ATYP | ADDR | PORT` is present when bit `0x01` of `FLAGS` is set and
+/// `RLEN | REASON` when bit `0x02` is set. `REASON` is UTF-8. Bit `0x04`
+/// acknowledges the early data of the request, it was written to the target.
+///
+/// Clients that send no [`Hello`] predate `FLAGS` and read the `CODE` byte
+/// alone, see [`write_legacy`]. They only know `0x01` for success, any other
+/// code is a failure to them.
+///
+/// [`Hello`]: crate::auth::Hello
+/// [`write_legacy`]: Self::write_legacy
+///
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub code: ResponseCode,
+    pub address: Option<Address>,
+    pub reason: Option<String>,
+    pub early_data: bool,
+}
+
+impl Response {
+    pub fn new(code: ResponseCode) -> Self {
+        Self {
+            code,
+            address: None,
+            reason: None,
+            early_data: false,
+        }
+    }
+
+    pub fn succeed() -> Self {
+        Self::new(ResponseCode::Succeed)
+    }
+
+    pub fn with_address(mut self, address: Address) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    pub fn with_reason<S: Into<String>>(mut self, reason: S) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// Acknowledges the early data of the request.
+    pub fn with_early_data(mut self) -> Self {
+        self.early_data = true;
+        self
+    }
+
+    pub fn is_succeed(&self) -> bool {
+        self.code == ResponseCode::Succeed
+    }
+
+    /// Writes the `CODE` byte alone, as read by clients predating `FLAGS`.
+    pub async fn write_legacy<T>(self, stream: &mut T) -> Result<()>
+    where
+        T: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        stream.write_u8(self.code.into()).await
+    }
+
+    /// Reads a response written by [`write_legacy`].
+    ///
+    /// [`write_legacy`]: Self::write_legacy
+    pub async fn read_legacy<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncReadExt + Unpin + Send + 'static,
+    {
+        let code = ResponseCode::from(stream.read_u8().await?);
+
+        Ok(Self::new(code))
+    }
 }
 
 impl ToBytes for Response {
     fn to_bytes(self) -> Bytes {
         let mut bytes = BytesMut::new();
 
-        match self {
-            Self::Succeed => {
-                bytes.put_u8(consts_response_type::SUCCEED);
+        let mut flags = 0;
+        if self.address.is_some() {
+            flags |= consts_response_flag::ADDRESS;
+        }
+        if self.reason.is_some() {
+            flags |= consts_response_flag::REASON;
+        }
+        if self.early_data {
+            flags |= consts_response_flag::EARLY_DATA;
+        }
+
+        bytes.put_u8(self.code.into());
+        bytes.put_u8(flags);
+
+        if let Some(address) = self.address {
+            bytes.extend(address.to_bytes());
+        }
+
+        if let Some(reason) = self.reason {
+            let mut len = reason.len().min(u8::MAX as usize);
+            while !reason.is_char_boundary(len) {
+                len -= 1;
             }
-            Self::NoAcceptableMethod => bytes.put_u8(consts_response_type::NO_ACCEPTABLE_REQUEST),
-        };
+
+            bytes.put_u8(len as u8);
+            bytes.extend_from_slice(&reason.as_bytes()[..len]);
+        }
 
         bytes.freeze()
     }
@@ -34,22 +136,187 @@ impl ToBytes for Response {
 impl Streamable for Response {
     async fn write<T>(self, stream: &mut T) -> Result<()>
     where
-        T: AsyncWriteExt + Unpin + Send,
+        T: AsyncWriteExt + Unpin + Send + 'static,
     {
         stream.write_all(&self.to_bytes()).await
     }
 
     async fn read<T>(stream: &mut T) -> Result<Self>
     where
-        T: AsyncReadExt + Unpin + Send,
+        T: AsyncReadExt + Unpin + Send + 'static,
     {
-        let response_type = stream.read_u8().await?;
+        let code = ResponseCode::from(stream.read_u8().await?);
+        let flags = stream.read_u8().await?;
 
-        let response = match response_type {
-            consts_response_type::SUCCEED => Self::Succeed,
-            _ => Self::NoAcceptableMethod,
-        };
+        let mut response = Self::new(code);
+        response.early_data = flags & consts_response_flag::EARLY_DATA != 0;
+
+        if flags & consts_response_flag::ADDRESS != 0 {
+            response.address = Some(Address::read(stream).await?);
+        }
+
+        if flags & consts_response_flag::REASON != 0 {
+            let reason_len = stream.read_u8().await? as usize;
+
+            let mut buffer = vec![0u8; reason_len];
+            stream.read_exact(&mut buffer).await?;
+
+            let reason =
+                String::from_utf8(buffer).map_err(|_| Error::other("invalid response reason"))?;
+
+            response.reason = Some(reason);
+        }
 
         Ok(response)
     }
 }
+
+// ===== ResponseCode =====
+#[rustfmt::skip]
+mod consts_response_code {
+    pub const SUCCEED:             u8 = 0x01;
+    pub const GENERAL_FAILURE:     u8 = 0x02;
+    pub const HOST_UNREACHABLE:    u8 = 0x03;
+    pub const CONNECTION_REFUSED:  u8 = 0x04;
+    pub const DNS_FAILURE:         u8 = 0x05;
+    pub const RULESET_DENIED:      u8 = 0x06;
+    pub const TTL_EXPIRED:         u8 = 0x07;
+    pub const AUTH_FAILED:         u8 = 0x08;
+    pub const UNSUPPORTED_REQUEST: u8 = 0xFF;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCode {
+    Succeed,
+    GeneralFailure,
+    HostUnreachable,
+    ConnectionRefused,
+    DnsFailure,
+    RulesetDenied,
+    TtlExpired,
+    AuthenticationFailed,
+    UnsupportedRequest,
+}
+
+impl From<ResponseCode> for u8 {
+    fn from(value: ResponseCode) -> Self {
+        match value {
+            ResponseCode::Succeed => consts_response_code::SUCCEED,
+            ResponseCode::GeneralFailure => consts_response_code::GENERAL_FAILURE,
+            ResponseCode::HostUnreachable => consts_response_code::HOST_UNREACHABLE,
+            ResponseCode::ConnectionRefused => consts_response_code::CONNECTION_REFUSED,
+            ResponseCode::DnsFailure => consts_response_code::DNS_FAILURE,
+            ResponseCode::RulesetDenied => consts_response_code::RULESET_DENIED,
+            ResponseCode::TtlExpired => consts_response_code::TTL_EXPIRED,
+            ResponseCode::AuthenticationFailed => consts_response_code::AUTH_FAILED,
+            ResponseCode::UnsupportedRequest => consts_response_code::UNSUPPORTED_REQUEST,
+        }
+    }
+}
+
+/// Unknown codes are read as [`ResponseCode::GeneralFailure`].
+impl From<u8> for ResponseCode {
+    fn from(value: u8) -> Self {
+        match value {
+            consts_response_code::SUCCEED => Self::Succeed,
+            consts_response_code::HOST_UNREACHABLE => Self::HostUnreachable,
+            consts_response_code::CONNECTION_REFUSED => Self::ConnectionRefused,
+            consts_response_code::DNS_FAILURE => Self::DnsFailure,
+            consts_response_code::RULESET_DENIED => Self::RulesetDenied,
+            consts_response_code::TTL_EXPIRED => Self::TtlExpired,
+            consts_response_code::AUTH_FAILED => Self::AuthenticationFailed,
+            consts_response_code::UNSUPPORTED_REQUEST => Self::UnsupportedRequest,
+            _ => Self::GeneralFailure,
+        }
+    }
+}
+
+impl From<&Error> for ResponseCode {
+    fn from(value: &Error) -> Self {
+        match value.kind() {
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => Self::HostUnreachable,
+            ErrorKind::TimedOut => Self::TtlExpired,
+            ErrorKind::Unsupported => Self::UnsupportedRequest,
+            _ => Self::GeneralFailure,
+        }
+    }
+}
+
+/// The error a failed [`Response`] stands for, the reverse of the mapping
+/// above.
+impl From<&Response> for Error {
+    fn from(value: &Response) -> Self {
+        let kind = match value.code {
+            ResponseCode::ConnectionRefused => ErrorKind::ConnectionRefused,
+            ResponseCode::HostUnreachable => ErrorKind::HostUnreachable,
+            ResponseCode::DnsFailure => ErrorKind::NotFound,
+            ResponseCode::RulesetDenied | ResponseCode::AuthenticationFailed => {
+                ErrorKind::PermissionDenied
+            }
+            ResponseCode::TtlExpired => ErrorKind::TimedOut,
+            ResponseCode::UnsupportedRequest => ErrorKind::Unsupported,
+            ResponseCode::Succeed | ResponseCode::GeneralFailure => ErrorKind::Other,
+        };
+
+        match &value.reason {
+            Some(reason) => Error::new(kind, reason.clone()),
+            None => Error::new(kind, format!("request failed with {:?}", value.code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn legacy_responses_are_the_code_alone() {
+        let mut bytes = Vec::new();
+        Response::succeed()
+            .with_address(Address::from(std::net::SocketAddr::from((
+                [127, 0, 0, 1],
+                80,
+            ))))
+            .with_reason("connected")
+            .write_legacy(&mut bytes)
+            .await
+            .unwrap();
+        assert_eq!(bytes, [0x01]);
+
+        // Written by servers predating response flags.
+        for (bytes, code) in [
+            ([0x01, b'x'], ResponseCode::Succeed),
+            ([0xFF, b'x'], ResponseCode::UnsupportedRequest),
+        ] {
+            let mut stream = Cursor::new(bytes.to_vec());
+            let response = Response::read_legacy(&mut stream).await.unwrap();
+            assert_eq!(response.code, code);
+            assert!(response.address.is_none());
+            assert_eq!(stream.read_u8().await.unwrap(), b'x');
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_read_back() {
+        let response = Response::new(ResponseCode::HostUnreachable)
+            .with_address(Address::from(std::net::SocketAddr::from((
+                [127, 0, 0, 1],
+                80,
+            ))))
+            .with_reason("no route")
+            .with_early_data();
+
+        let mut bytes = Vec::new();
+        response.write(&mut bytes).await.unwrap();
+        assert_eq!(bytes[..2], [0x03, 0b0000_0111]);
+
+        let read = Response::read(&mut Cursor::new(bytes)).await.unwrap();
+        assert_eq!(read.code, ResponseCode::HostUnreachable);
+        assert!(matches!(read.address, Some(Address::IPv4(_))));
+        assert_eq!(read.reason.as_deref(), Some("no route"));
+        assert!(read.early_data);
+    }
+}
@@ -1,31 +1,98 @@
+use std::time::Duration;
 use std::{marker::PhantomData, sync::Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result};
 
-use crate::{Provider, Resolver};
+use crate::resolver::system::SystemResolver;
+use crate::{Authenticator, Provider, Resolver};
+
+const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_CONNECT_DELAY: Duration = Duration::from_millis(250);
+const DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct ServerHandlerContextBuilder<RE> {
-    resolver: Option<RE>,
+    resolver: RE,
+    authenticator: Option<Box<dyn Authenticator>>,
+    udp_timeout: Duration,
+    connect_delay: Duration,
+    bind_timeout: Duration,
 }
 
-impl<RE> ServerHandlerContextBuilder<RE> {
+impl Default for ServerHandlerContextBuilder<SystemResolver> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerHandlerContextBuilder<SystemResolver> {
+    /// Resolves with a [`SystemResolver`] unless another resolver is set.
     pub fn new() -> Self {
-        Self { resolver: None }
+        Self {
+            resolver: SystemResolver::new(),
+            authenticator: None,
+            udp_timeout: DEFAULT_UDP_TIMEOUT,
+            connect_delay: DEFAULT_CONNECT_DELAY,
+            bind_timeout: DEFAULT_BIND_TIMEOUT,
+        }
+    }
+}
+
+impl<RE> ServerHandlerContextBuilder<RE> {
+    pub fn with_resolver<R>(self, resolver: R) -> ServerHandlerContextBuilder<R> {
+        ServerHandlerContextBuilder {
+            resolver,
+            authenticator: self.authenticator,
+            udp_timeout: self.udp_timeout,
+            connect_delay: self.connect_delay,
+            bind_timeout: self.bind_timeout,
+        }
     }
 
-    pub fn with_resolver(mut self, resolver: RE) -> Self {
-        self.resolver = Some(resolver);
+    /// Requires every stream to start with a [`Hello`] accepted by
+    /// `authenticator`, streams are open to anyone otherwise.
+    ///
+    /// [`Hello`]: crate::auth::Hello
+    pub fn with_authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Box::new(authenticator));
+        self
+    }
+
+    /// Idle time after which a UDP association is dropped.
+    pub fn with_udp_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_timeout = timeout;
+        self
+    }
+
+    /// How long a connect attempt to one address of a target gets before
+    /// the next address is tried alongside it.
+    pub fn with_connect_delay(mut self, delay: Duration) -> Self {
+        self.connect_delay = delay;
+        self
+    }
+
+    /// How long a `TCPBind` listens for its inbound connection before the
+    /// port is released.
+    pub fn with_bind_timeout(mut self, timeout: Duration) -> Self {
+        self.bind_timeout = timeout;
         self
     }
 
     pub fn build(self) -> ServerHandlerContext<RE> {
         ServerHandlerContext {
-            resolver: self.resolver.expect("Resolver must be provided"),
+            resolver: self.resolver,
+            authenticator: self.authenticator,
+            udp_timeout: self.udp_timeout,
+            connect_delay: self.connect_delay,
+            bind_timeout: self.bind_timeout,
         }
     }
 }
 
 pub struct ServerHandlerContext<RE> {
     resolver: RE,
+    authenticator: Option<Box<dyn Authenticator>>,
+    udp_timeout: Duration,
+    connect_delay: Duration,
+    bind_timeout: Duration,
 }
 
 pub struct ServerBuilder<R, RE, RS> {
@@ -34,6 +101,17 @@ pub struct ServerBuilder<R, RE, RS> {
     _accept_stream: PhantomData<RS>,
 }
 
+impl<R, RE, RS> Default for ServerBuilder<R, RE, RS>
+where
+    R: Provider<RS>,
+    RE: Resolver + 'static,
+    RS: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<R, RE, RS> ServerBuilder<R, RE, RS>
 where
     R: Provider<RS>,
@@ -88,25 +166,624 @@ where
 
     async fn handler(mut stream: RS, context: &ServerHandlerContext<RE>) -> Result<()> {
         use tokio::io::copy_bidirectional;
-        use tokio::net::TcpStream;
 
+        use crate::auth::{Hello, ServerHello};
         use crate::request::Request;
-        use crate::response::Response;
+        use crate::response::{Response, ResponseCode};
+        use crate::transport::tcp::connect_happy_eyeballs;
         use crate::Streamable;
 
-        let request = Request::read(&mut stream).await?;
+        let mut request_type = stream.read_u8().await?;
+
+        // A hello announces the client's version and may authenticate it,
+        // servers without an authenticator skip the credentials.
+        let mut hello = None;
+        if Hello::is_marker(request_type) {
+            let read = Hello::read_after_marker(&mut stream).await;
+
+            match &read {
+                Ok(read) if read.expects_answer() => ServerHello::new().write(&mut stream).await?,
+                // The rest of the stream is in a layout this server does not
+                // know, the client learns which versions it does instead.
+                Err(error) if error.kind() == ErrorKind::Unsupported => {
+                    ServerHello::new().write(&mut stream).await?;
+                    return read.map(|_| ());
+                }
+                _ => {}
+            }
+
+            if read.is_ok() {
+                request_type = stream.read_u8().await?;
+            }
+            hello = Some(read);
+        }
+
+        // Clients sending no hello predate response flags.
+        let legacy = hello.is_none();
+
+        if let Some(authenticator) = &context.authenticator {
+            Self::authenticate(&mut stream, authenticator.as_ref(), hello).await?;
+        } else if let Some(Err(error)) = hello {
+            return Self::reject(&mut stream, legacy, (&error).into(), error).await;
+        }
+
+        let (request, extensions) = match Request::read_after_type(request_type, &mut stream).await
+        {
+            Ok(request) => request,
+            Err(error) if error.kind() == ErrorKind::Unsupported => {
+                return Self::reject(&mut stream, legacy, ResponseCode::UnsupportedRequest, error)
+                    .await
+            }
+            Err(error) => return Err(error),
+        };
 
         match request {
             Request::TCPConnect(addr) => {
-                let address = addr.to_socket_address(&context.resolver).await?;
-                let mut connect = TcpStream::connect(address).await?;
+                let addresses = match addr.to_socket_addresses(&context.resolver).await {
+                    Ok(addresses) => addresses,
+                    Err(error) => {
+                        return Self::reject(&mut stream, legacy, ResponseCode::DnsFailure, error)
+                            .await
+                    }
+                };
 
-                Response::Succeed.write(&mut stream).await?;
+                let connect = connect_happy_eyeballs(addresses, context.connect_delay);
+                let connect =
+                    match extensions.timeout() {
+                        Some(timeout) => tokio::time::timeout(timeout, connect)
+                            .await
+                            .unwrap_or_else(|_| {
+                                Err(Error::new(ErrorKind::TimedOut, "connect timed out"))
+                            }),
+                        None => connect.await,
+                    };
 
-                copy_bidirectional(&mut stream, &mut connect).await?
+                let mut connect = match connect {
+                    Ok(connect) => connect,
+                    Err(error) => {
+                        return Self::reject(&mut stream, legacy, (&error).into(), error).await
+                    }
+                };
+
+                let mut response = Response::succeed().with_address(connect.local_addr()?.into());
+
+                if let Some(early_data) = extensions.early_data() {
+                    if let Err(error) = connect.write_all(early_data).await {
+                        return Self::reject(&mut stream, legacy, (&error).into(), error).await;
+                    }
+                    response = response.with_early_data();
+                }
+
+                Self::respond(&mut stream, legacy, response).await?;
+
+                copy_bidirectional(&mut stream, &mut connect).await?;
+            }
+
+            Request::UDPAssociate => {
+                let socket = match Self::udp_bind().await {
+                    Ok(socket) => socket,
+                    Err(error) => {
+                        return Self::reject(&mut stream, legacy, (&error).into(), error).await
+                    }
+                };
+
+                let response = Response::succeed().with_address(socket.local_addr()?.into());
+                Self::respond(&mut stream, legacy, response).await?;
+
+                Self::udp_relay(stream, socket, context).await?;
+            }
+
+            Request::TCPBind(addr) => {
+                use tokio::net::TcpListener;
+
+                let address = match addr.to_socket_address(&context.resolver).await {
+                    Ok(address) => address,
+                    Err(error) => {
+                        return Self::reject(&mut stream, legacy, ResponseCode::DnsFailure, error)
+                            .await
+                    }
+                };
+
+                let listener = match TcpListener::bind(address).await {
+                    Ok(listener) => listener,
+                    Err(error) => {
+                        return Self::reject(&mut stream, legacy, (&error).into(), error).await
+                    }
+                };
+
+                let response = Response::succeed().with_address(listener.local_addr()?.into());
+                Self::respond(&mut stream, legacy, response).await?;
+
+                // The port is only held for as long as the client waits.
+                let accept = tokio::select! {
+                    accept = tokio::time::timeout(context.bind_timeout, listener.accept()) => {
+                        accept.unwrap_or_else(|_| {
+                            Err(Error::new(ErrorKind::TimedOut, "no inbound connection to bind"))
+                        })
+                    }
+                    error = Self::closed(&mut stream) => return Err(error),
+                };
+
+                let (mut inbound, peer) = match accept {
+                    Ok(accept) => accept,
+                    Err(error) => {
+                        return Self::reject(&mut stream, legacy, (&error).into(), error).await
+                    }
+                };
+                drop(listener);
+
+                let response = Response::succeed().with_address(peer.into());
+                Self::respond(&mut stream, legacy, response).await?;
+
+                copy_bidirectional(&mut stream, &mut inbound).await?;
             }
         };
 
         Ok(())
     }
+
+    async fn authenticate(
+        stream: &mut RS,
+        authenticator: &dyn Authenticator,
+        hello: Option<Result<crate::auth::Hello>>,
+    ) -> Result<()> {
+        use crate::response::ResponseCode;
+
+        let legacy = hello.is_none();
+        let result = match hello {
+            Some(Ok(hello)) => authenticator
+                .authenticate(&hello)
+                .map_err(|error| (Some(hello.user), error)),
+            Some(Err(error)) => Err((None, error)),
+            None => Err((
+                None,
+                Error::new(ErrorKind::PermissionDenied, "missing hello"),
+            )),
+        };
+
+        let Err((user, error)) = result else {
+            return Ok(());
+        };
+
+        log::warn!(
+            "authentication failed for {}: {}",
+            user.as_deref().unwrap_or("unknown user"),
+            error
+        );
+
+        // The reason stays in the log, the peer learns nothing about it.
+        let error = Error::new(ErrorKind::PermissionDenied, "authentication failed");
+        Self::reject(stream, legacy, ResponseCode::AuthenticationFailed, error).await
+    }
+
+    async fn reject(
+        stream: &mut RS,
+        legacy: bool,
+        code: crate::response::ResponseCode,
+        error: Error,
+    ) -> Result<()> {
+        use crate::response::Response;
+
+        let response = Response::new(code).with_reason(error.to_string());
+        Self::respond(stream, legacy, response).await?;
+
+        Err(error)
+    }
+
+    /// Writes `response`, only its code to `legacy` clients.
+    async fn respond(
+        stream: &mut RS,
+        legacy: bool,
+        response: crate::response::Response,
+    ) -> Result<()> {
+        use crate::Streamable;
+
+        match legacy {
+            true => response.write_legacy(stream).await,
+            false => response.write(stream).await,
+        }
+    }
+
+    /// Resolves once the client closes a stream it is not meant to send on
+    /// yet, or sends on it anyway.
+    async fn closed(stream: &mut RS) -> Error {
+        let mut byte = [0u8; 1];
+
+        match stream.read(&mut byte).await {
+            Ok(0) => Error::new(ErrorKind::ConnectionAborted, "tunnel closed by the client"),
+            Ok(_) => Error::new(ErrorKind::InvalidData, "unexpected data from the client"),
+            Err(error) => error,
+        }
+    }
+
+    async fn udp_bind() -> Result<tokio::net::UdpSocket> {
+        use std::net::{Ipv4Addr, Ipv6Addr};
+        use tokio::net::UdpSocket;
+
+        match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+            Ok(socket) => Ok(socket),
+            Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await,
+        }
+    }
+
+    async fn udp_relay(
+        stream: RS,
+        socket: tokio::net::UdpSocket,
+        context: &ServerHandlerContext<RE>,
+    ) -> Result<()> {
+        use std::net::SocketAddr;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use tokio::time::{sleep, Instant};
+
+        use crate::request::{Address, Datagram};
+        use crate::Streamable;
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        let start = Instant::now();
+        let last_active = AtomicU64::new(0);
+        let touch = || last_active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+        let local_v6 = socket.local_addr()?.is_ipv6();
+
+        let uplink = async {
+            loop {
+                let datagram = Datagram::read(&mut reader).await?;
+                touch();
+
+                let target = match datagram.address.to_socket_address(&context.resolver).await {
+                    Ok(SocketAddr::V4(addr)) if local_v6 => {
+                        SocketAddr::new(addr.ip().to_ipv6_mapped().into(), addr.port())
+                    }
+                    Ok(addr) => addr,
+                    Err(_) => continue,
+                };
+
+                let _ = socket.send_to(&datagram.payload, target).await;
+            }
+        };
+
+        let downlink = async {
+            let mut buffer = vec![0u8; u16::MAX as usize];
+
+            loop {
+                let (len, source) = socket.recv_from(&mut buffer).await?;
+                touch();
+
+                let datagram = Datagram::new(
+                    Address::from(source),
+                    bytes::Bytes::copy_from_slice(&buffer[..len]),
+                );
+                datagram.write(&mut writer).await?;
+            }
+        };
+
+        let idle = async {
+            loop {
+                let last = Duration::from_millis(last_active.load(Ordering::Relaxed));
+                let deadline = start + last + context.udp_timeout;
+
+                if Instant::now() >= deadline {
+                    break;
+                }
+
+                sleep(deadline - Instant::now()).await;
+            }
+        };
+
+        tokio::select! {
+            result = uplink => result,
+            result = downlink => result,
+            _ = idle => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::net::TcpStream;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::request::{Address, Request};
+    use crate::response::{Response, ResponseCode};
+    use crate::Streamable;
+
+    struct NoStreams;
+
+    impl Provider<DuplexStream> for NoStreams {
+        async fn fetch(&mut self) -> Option<DuplexStream> {
+            None
+        }
+    }
+
+    type TestServer = Server<NoStreams, SystemResolver, DuplexStream>;
+
+    /// Runs a handler for a client that sent the current hello, the answer
+    /// to it is read already.
+    async fn start(
+        context: ServerHandlerContext<SystemResolver>,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        use crate::auth::{Hello, ServerHello};
+
+        let (mut client, server) = duplex(1024);
+        let handler = tokio::spawn(async move { TestServer::handler(server, &context).await });
+
+        Hello::anonymous().write(&mut client).await.unwrap();
+        ServerHello::read(&mut client)
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+
+        (client, handler)
+    }
+
+    /// Sends a bind for a loopback port and returns the bound address.
+    async fn bind(
+        context: ServerHandlerContext<SystemResolver>,
+    ) -> (DuplexStream, SocketAddr, JoinHandle<Result<()>>) {
+        let (mut client, handler) = start(context).await;
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 0));
+        Request::TCPBind(address.into())
+            .write(&mut client)
+            .await
+            .unwrap();
+
+        let response = Response::read(&mut client).await.unwrap();
+        assert!(response.is_succeed());
+        let Some(Address::IPv4(bound)) = response.address else {
+            panic!("bound address missing");
+        };
+
+        (client, bound.into(), handler)
+    }
+
+    #[tokio::test]
+    async fn connect_acknowledges_early_data() {
+        use tokio::net::TcpListener;
+
+        use crate::request::Extensions;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = target.local_addr().unwrap();
+
+        for early_data in [None, Some(Bytes::from_static(b"hello"))] {
+            let (mut client, _handler) = start(ServerHandlerContextBuilder::new().build()).await;
+
+            let mut extensions = Extensions::new();
+            if let Some(early_data) = &early_data {
+                extensions = extensions.with_early_data(early_data.clone());
+            }
+            Request::TCPConnect(address.into())
+                .write_with_extensions(extensions, &mut client)
+                .await
+                .unwrap();
+
+            let (mut inbound, _) = target.accept().await.unwrap();
+            let response = Response::read(&mut client).await.unwrap();
+            assert!(response.is_succeed());
+            assert_eq!(response.early_data, early_data.is_some());
+
+            if let Some(early_data) = early_data {
+                let mut buffer = vec![0u8; early_data.len()];
+                inbound.read_exact(&mut buffer).await.unwrap();
+                assert_eq!(buffer, early_data);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_splices_the_inbound_connection() {
+        let context = ServerHandlerContextBuilder::new().build();
+        let (mut client, bound, _handler) = bind(context).await;
+
+        let mut inbound = TcpStream::connect(bound).await.unwrap();
+
+        let response = Response::read(&mut client).await.unwrap();
+        assert!(response.is_succeed());
+        let Some(Address::IPv4(peer)) = response.address else {
+            panic!("peer address missing");
+        };
+        assert_eq!(SocketAddr::from(peer), inbound.local_addr().unwrap());
+
+        inbound.write_all(b"ping").await.unwrap();
+        let mut buffer = [0u8; 4];
+        client.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        client.write_all(b"pong").await.unwrap();
+        inbound.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"pong");
+    }
+
+    #[tokio::test]
+    async fn bind_times_out_without_inbound_connection() {
+        let context = ServerHandlerContextBuilder::new()
+            .with_bind_timeout(Duration::from_millis(100))
+            .build();
+        let (mut client, bound, handler) = bind(context).await;
+
+        let response = Response::read(&mut client).await.unwrap();
+        assert_eq!(response.code, ResponseCode::TtlExpired);
+
+        let error = handler.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(TcpStream::connect(bound).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_is_released_when_the_client_goes_away() {
+        let context = ServerHandlerContextBuilder::new().build();
+        let (client, bound, handler) = bind(context).await;
+
+        drop(client);
+
+        let error = tokio::time::timeout(Duration::from_secs(5), handler)
+            .await
+            .expect("handler must stop with the client")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionAborted);
+        assert!(TcpStream::connect(bound).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_associate_relays_datagrams_until_idle() {
+        use tokio::net::UdpSocket;
+
+        use crate::request::Datagram;
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (len, source) = echo.recv_from(&mut buffer).await.unwrap();
+                echo.send_to(&buffer[..len], source).await.unwrap();
+            }
+        });
+
+        let context = ServerHandlerContextBuilder::new()
+            .with_udp_timeout(Duration::from_millis(300))
+            .build();
+        let (mut client, handler) = start(context).await;
+
+        Request::UDPAssociate.write(&mut client).await.unwrap();
+        let response = Response::read(&mut client).await.unwrap();
+        assert!(response.is_succeed());
+        assert!(response.address.is_some());
+
+        for payload in [&b"ping"[..], b"pong"] {
+            Datagram::new(target.into(), Bytes::from_static(payload))
+                .write(&mut client)
+                .await
+                .unwrap();
+
+            let datagram = Datagram::read(&mut client).await.unwrap();
+            let Address::IPv4(source) = datagram.address else {
+                panic!("source address is not IPv4");
+            };
+            assert_eq!(SocketAddr::from(source), target);
+            assert_eq!(datagram.payload.as_ref(), payload);
+        }
+
+        // The association ends on its own once nothing is relayed.
+        let started = tokio::time::Instant::now();
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+            .await
+            .expect("association must end when idle")
+            .unwrap();
+        assert!(rest.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn hello_is_answered_with_the_supported_versions() {
+        use tokio::net::TcpListener;
+
+        use crate::auth::{Hello, ServerHello};
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, server) = duplex(1024);
+        let context = ServerHandlerContextBuilder::new().build();
+        tokio::spawn(async move { TestServer::handler(server, &context).await });
+
+        Hello::anonymous().write(&mut client).await.unwrap();
+        Request::TCPConnect(target.local_addr().unwrap().into())
+            .write(&mut client)
+            .await
+            .unwrap();
+
+        let server_hello = ServerHello::read(&mut client).await.unwrap();
+        assert_eq!(server_hello, ServerHello::new());
+        assert!(Response::read(&mut client).await.unwrap().is_succeed());
+    }
+
+    #[tokio::test]
+    async fn unsupported_hello_is_answered_and_closed() {
+        use crate::auth::ServerHello;
+
+        let (mut client, server) = duplex(1024);
+        let context = ServerHandlerContextBuilder::new().build();
+        let handler = tokio::spawn(async move { TestServer::handler(server, &context).await });
+
+        // A later version may lay out the rest of its hello differently.
+        client.write_all(&[0xF0, 0x7F, 0xAA, 0xBB]).await.unwrap();
+
+        let server_hello = ServerHello::read(&mut client).await.unwrap();
+        assert_eq!(server_hello, ServerHello::new());
+
+        let error = handler.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn first_version_hellos_are_not_answered() {
+        use crate::auth::{Credentials, Hello, PskAuthenticator};
+
+        let (mut client, server) = duplex(1024);
+        let context = ServerHandlerContextBuilder::new()
+            .with_authenticator(PskAuthenticator::new().with_user("alice", "secret"))
+            .build();
+        tokio::spawn(async move { TestServer::handler(server, &context).await });
+
+        // Clients of version 0x01 read the response right after the request.
+        let mut hello = Hello::new(&Credentials::new("alice", "other")).unwrap();
+        hello.version = 0x01;
+        hello.write(&mut client).await.unwrap();
+        Request::UDPAssociate.write(&mut client).await.unwrap();
+
+        let response = Response::read(&mut client).await.unwrap();
+        assert_eq!(response.code, ResponseCode::AuthenticationFailed);
+    }
+
+    #[tokio::test]
+    async fn clients_without_hello_get_the_code_alone() {
+        use tokio::net::TcpListener;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = target.local_addr().unwrap();
+
+        let (mut client, server) = duplex(1024);
+        let context = ServerHandlerContextBuilder::new().build();
+        tokio::spawn(async move { TestServer::handler(server, &context).await });
+
+        Request::TCPConnect(address.into())
+            .write(&mut client)
+            .await
+            .unwrap();
+        let (mut inbound, _) = target.accept().await.unwrap();
+
+        // Anything after the code is relayed from the target.
+        inbound.write_all(b"ping").await.unwrap();
+        let mut buffer = [0u8; 5];
+        client.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [0x01, b'p', b'i', b'n', b'g']);
+
+        drop(target);
+        let (mut client, server) = duplex(1024);
+        let context = ServerHandlerContextBuilder::new().build();
+        tokio::spawn(async move { TestServer::handler(server, &context).await });
+
+        Request::TCPConnect(address.into())
+            .write(&mut client)
+            .await
+            .unwrap();
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, [u8::from(ResponseCode::ConnectionRefused)]);
+    }
 }
@@ -0,0 +1,582 @@
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::frame::{Codec, FramedStream};
+use crate::Provider;
+
+const SUBKEY_INFO: &[u8] = b"quicos-aead-subkey";
+
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const MAX_PAYLOAD_SIZE: usize = 0x3FFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    ChaCha20Poly1305,
+    Aes128Gcm,
+    Aes256Gcm,
+}
+
+impl Cipher {
+    /// Size of the session key, and of the salt it is derived with.
+    fn key_size(self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 | Self::Aes256Gcm => 32,
+            Self::Aes128Gcm => 16,
+        }
+    }
+}
+
+// The AES key schedules are large, keep them off the stream itself.
+enum SessionCipher {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+/// One direction of a stream, keyed with a subkey of the pre-shared key and
+/// the salts of both peers. Nonces count up from zero per chunk.
+struct Session {
+    cipher: SessionCipher,
+    counter: u64,
+}
+
+impl Session {
+    fn new(cipher: Cipher, key: &[u8], salt: &[u8]) -> Self {
+        let subkey = subkey(cipher, key, salt);
+
+        let cipher = match cipher {
+            Cipher::ChaCha20Poly1305 => SessionCipher::ChaCha20Poly1305(
+                ChaCha20Poly1305::new_from_slice(&subkey).expect("subkey size matches"),
+            ),
+            Cipher::Aes128Gcm => SessionCipher::Aes128Gcm(Box::new(
+                Aes128Gcm::new_from_slice(&subkey).expect("subkey size matches"),
+            )),
+            Cipher::Aes256Gcm => SessionCipher::Aes256Gcm(Box::new(
+                Aes256Gcm::new_from_slice(&subkey).expect("subkey size matches"),
+            )),
+        };
+
+        Self { cipher, counter: 0 }
+    }
+
+    fn nonce(&mut self) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce();
+        let nonce = (&nonce).into();
+
+        match &self.cipher {
+            SessionCipher::ChaCha20Poly1305(cipher) => cipher.encrypt(nonce, plaintext),
+            SessionCipher::Aes128Gcm(cipher) => cipher.encrypt(nonce, plaintext),
+            SessionCipher::Aes256Gcm(cipher) => cipher.encrypt(nonce, plaintext),
+        }
+        .expect("chunk fits the cipher limits")
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce();
+        let nonce = (&nonce).into();
+
+        match &self.cipher {
+            SessionCipher::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce, ciphertext),
+            SessionCipher::Aes128Gcm(cipher) => cipher.decrypt(nonce, ciphertext),
+            SessionCipher::Aes256Gcm(cipher) => cipher.decrypt(nonce, ciphertext),
+        }
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "aead chunk failed authentication"))
+    }
+}
+
+fn subkey(cipher: Cipher, key: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut subkey = vec![0u8; cipher.key_size()];
+    hkdf_sha256(key, salt, SUBKEY_INFO, &mut subkey);
+
+    subkey
+}
+
+/// HKDF-SHA256 extract and expand of [RFC 5869].
+///
+/// [RFC 5869]: https://www.rfc-editor.org/rfc/rfc5869
+fn hkdf_sha256(key: &[u8], salt: &[u8], info: &[u8], output: &mut [u8]) {
+    Hkdf::<Sha256>::new(Some(salt), key)
+        .expand(info, output)
+        .expect("output fits HKDF");
+}
+
+/// Frames of an [`AeadStream`], a salt ahead of chunks each preceded by
+/// their sealed length.
+struct AeadCodec {
+    cipher: Cipher,
+    key: Arc<[u8]>,
+    salt: Vec<u8>,
+    reader: Option<Session>,
+    writer: Option<Session>,
+}
+
+impl Codec for AeadCodec {
+    fn preamble_size(&self) -> usize {
+        self.cipher.key_size()
+    }
+
+    /// Each direction is keyed on the salt of its sender, then the other.
+    fn open_preamble(&mut self, salt: &[u8]) -> Result<()> {
+        let reader = [salt, &self.salt].concat();
+        let writer = [&self.salt, salt].concat();
+
+        self.reader = Some(Session::new(self.cipher, &self.key, &reader));
+        self.writer = Some(Session::new(self.cipher, &self.key, &writer));
+        Ok(())
+    }
+
+    fn header_size(&self) -> usize {
+        2 + TAG_SIZE
+    }
+
+    fn open_header(&mut self, header: &[u8]) -> Result<usize> {
+        let reader = self.reader.as_mut().expect("salt read before length");
+        let length = reader.open(header)?;
+        let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+        if length == 0 || length > MAX_PAYLOAD_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid aead chunk length {}", length),
+            ));
+        }
+
+        Ok(length + TAG_SIZE)
+    }
+
+    fn open_body(&mut self, body: &[u8]) -> Result<Bytes> {
+        let reader = self.reader.as_mut().expect("salt read before payload");
+        reader.open(body).map(Bytes::from)
+    }
+
+    fn max_payload_size(&self) -> usize {
+        MAX_PAYLOAD_SIZE
+    }
+
+    fn seal(&mut self, plaintext: &[u8], frame: &mut BytesMut) -> Result<()> {
+        let writer = self.writer.as_mut().expect("salt read before sealing");
+        frame.extend_from_slice(&writer.seal(&(plaintext.len() as u16).to_be_bytes()));
+        frame.extend_from_slice(&writer.seal(plaintext));
+        Ok(())
+    }
+}
+
+/// A stream encrypted with a pre-shared key, without any handshake.
+///
+/// ## Bytes
+/// ```text
+///          +----------+------------+------------+-----+
+///          |   SALT   | LENGTH+TAG | DATA+TAG   | ... |
+///          +----------+------------+------------+-----+
+///          | Key size |   2 + 16   | LEN + 16   |     |
+///          +----------+------------+------------+-----+
+/// ```
+///
+/// Each direction starts with a random salt, the session key of a direction
+/// is derived from the pre-shared key and the salts of its sender and then of
+/// its receiver with HKDF-SHA256. As both peers pick a fresh salt, a recorded
+/// stream replayed to either of them fails authentication. Writes wait for
+/// the peer's salt, so nothing but the salt is sent before it arrives.
+///
+/// Every write is sent as chunks of at most 16383 bytes, each preceded by
+/// its sealed length, so neither payload nor chunk boundaries are visible on
+/// the wire. Any chunk failing authentication fails the read with
+/// `InvalidData`.
+pub struct AeadStream<S> {
+    inner: FramedStream<S, AeadCodec>,
+}
+
+impl<S> AeadStream<S> {
+    pub fn new(inner: S, cipher: Cipher, key: Arc<[u8]>) -> Result<Self> {
+        let mut salt = vec![0u8; cipher.key_size()];
+        getrandom::fill(&mut salt).map_err(Error::from)?;
+
+        Ok(Self::with_salt(inner, cipher, key, &salt))
+    }
+
+    fn with_salt(inner: S, cipher: Cipher, key: Arc<[u8]>, salt: &[u8]) -> Self {
+        let codec = AeadCodec {
+            cipher,
+            key,
+            salt: salt.to_vec(),
+            reader: None,
+            writer: None,
+        };
+
+        Self {
+            inner: FramedStream::new(inner, codec, salt),
+        }
+    }
+}
+
+impl<S> AsyncRead for AeadStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for AeadStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Wraps every stream fetched from the wrapped provider in an [`AeadStream`],
+/// on the accept side of a `Server` and the connect side of a `Client` alike.
+/// Both sides must use the same cipher and key.
+pub struct AeadProvider<P, S> {
+    inner: P,
+    cipher: Cipher,
+    key: Arc<[u8]>,
+    _stream: PhantomData<S>,
+}
+
+impl<P, S> AeadProvider<P, S> {
+    pub fn new<K: AsRef<[u8]>>(inner: P, cipher: Cipher, key: K) -> Self {
+        Self {
+            inner,
+            cipher,
+            key: Arc::from(key.as_ref()),
+            _stream: PhantomData,
+        }
+    }
+}
+
+impl<P, S> Provider<AeadStream<S>> for AeadProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: Send,
+{
+    async fn fetch(&mut self) -> Option<AeadStream<S>> {
+        loop {
+            let stream = self.inner.fetch().await?;
+
+            if let Ok(stream) = AeadStream::new(stream, self.cipher, self.key.clone()) {
+                return Some(stream);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const KEY: &[u8] = b"quicos test key";
+    const PLAINTEXT: &[u8] = b"hello";
+
+    /// Subkey and the sealed length and payload chunks of `PLAINTEXT`, with
+    /// the sender's salt counting up from zero and the receiver's on from
+    /// there. Computed with the Python `cryptography` package.
+    #[rustfmt::skip]
+    const VECTORS: [(Cipher, &str, &str); 3] = [
+        (
+            Cipher::ChaCha20Poly1305,
+            "f638d8b0e8d9ee828e0f5c52f991d65c621d25a24ffb9b89471421c363babb93",
+            "b486ff9c78f4883e54063119441a8dea7fdcd8d6c89e2dc484781f6f67f2a3d4146ca6eb330334",
+        ),
+        (
+            Cipher::Aes128Gcm,
+            "6f094e32781efdfbf430c46f499106be",
+            "823760d6c1babc1d68ece4d76154f29364d4ce58a222c6f63198075d1e77233f4a3e6a41b78674",
+        ),
+        (
+            Cipher::Aes256Gcm,
+            "f638d8b0e8d9ee828e0f5c52f991d65c621d25a24ffb9b89471421c363babb93",
+            "bd8531ccd1f2b5694cb320dbd92348c244d0daa462b853d6286b765bb98bd0fb9217430a37de82",
+        ),
+    ];
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn sender_salt(cipher: Cipher) -> Vec<u8> {
+        (0..cipher.key_size() as u8).collect()
+    }
+
+    fn receiver_salt(cipher: Cipher) -> Vec<u8> {
+        let size = cipher.key_size() as u8;
+        (size..size * 2).collect()
+    }
+
+    /// The wire bytes of `PLAINTEXT` sent by a stream with a known salt.
+    fn sealed(cipher: Cipher, chunks: &str) -> Vec<u8> {
+        [sender_salt(cipher), hex(chunks)].concat()
+    }
+
+    /// Reads `wire` to its end through an `AeadStream` with `salt`.
+    async fn open_with(cipher: Cipher, salt: &[u8], wire: &[u8]) -> Result<Vec<u8>> {
+        let (inner, mut peer) = duplex(1024);
+        peer.write_all(wire).await.unwrap();
+        drop(peer);
+
+        let mut stream = AeadStream::with_salt(inner, cipher, KEY.into(), salt);
+        let mut plaintext = Vec::new();
+        stream.read_to_end(&mut plaintext).await?;
+
+        Ok(plaintext)
+    }
+
+    /// Reads `wire` as the receiver of the vectors.
+    async fn open(cipher: Cipher, wire: &[u8]) -> Result<Vec<u8>> {
+        open_with(cipher, &receiver_salt(cipher), wire).await
+    }
+
+    #[test]
+    fn hkdf_matches_rfc_5869() {
+        // Test case 1 of RFC 5869, appendix A.1.
+        let key = [0x0b; 22];
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+
+        let mut output = [0u8; 42];
+        hkdf_sha256(&key, &salt, &info, &mut output);
+        assert_eq!(
+            output.to_vec(),
+            hex(concat!(
+                "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db0",
+                "2d56ecc4c5bf34007208d5b887185865"
+            ))
+        );
+    }
+
+    #[test]
+    fn subkeys_match_vectors() {
+        for (cipher, subkey, _) in VECTORS {
+            let salt = [sender_salt(cipher), receiver_salt(cipher)].concat();
+            assert_eq!(
+                super::subkey(cipher, KEY, &salt),
+                hex(subkey),
+                "{:?}",
+                cipher
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_match_vectors() {
+        for (cipher, _, chunks) in VECTORS {
+            let (inner, mut peer) = duplex(1024);
+            peer.write_all(&receiver_salt(cipher)).await.unwrap();
+
+            let mut stream = AeadStream::with_salt(inner, cipher, KEY.into(), &sender_salt(cipher));
+            stream.write_all(PLAINTEXT).await.unwrap();
+            stream.shutdown().await.unwrap();
+            drop(stream);
+
+            let mut wire = Vec::new();
+            peer.read_to_end(&mut wire).await.unwrap();
+            assert_eq!(wire, sealed(cipher, chunks), "{:?}", cipher);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_match_vectors() {
+        for (cipher, _, chunks) in VECTORS {
+            let plaintext = open(cipher, &sealed(cipher, chunks)).await.unwrap();
+            assert_eq!(plaintext, PLAINTEXT, "{:?}", cipher);
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_streams_fail_authentication() {
+        for (cipher, _, chunks) in VECTORS {
+            // The same bytes received by a stream that picked another salt.
+            let error = open_with(cipher, &sender_salt(cipher), &sealed(cipher, chunks))
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", cipher);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_wait_for_the_peer_salt() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let (inner, mut peer) = duplex(1024);
+
+        let mut stream = AeadStream::new(inner, cipher, KEY.into()).unwrap();
+        let write = tokio::spawn(async move {
+            stream.write_all(PLAINTEXT).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        // Only the salt goes out until the peer's arrives.
+        let mut salt = vec![0u8; cipher.key_size()];
+        peer.read_exact(&mut salt).await.unwrap();
+        let early = tokio::time::timeout(Duration::from_secs(1), peer.read(&mut [0u8; 1])).await;
+        assert!(early.is_err(), "nothing but the salt may be sent");
+
+        peer.write_all(&receiver_salt(cipher)).await.unwrap();
+        write.await.unwrap();
+
+        let mut chunks = Vec::new();
+        peer.read_to_end(&mut chunks).await.unwrap();
+        assert_eq!(chunks.len(), 2 + TAG_SIZE + PLAINTEXT.len() + TAG_SIZE);
+    }
+
+    #[tokio::test]
+    async fn split_halves_both_get_the_peer_salt() {
+        let cipher = Cipher::ChaCha20Poly1305;
+        let (client, server) = duplex(1024);
+        let client = AeadStream::new(client, cipher, KEY.into()).unwrap();
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        // Both halves wait on the salt, only one of them polls for it last.
+        let read = tokio::spawn(async move {
+            let mut plaintext = [0u8; PLAINTEXT.len()];
+            reader.read_exact(&mut plaintext).await.map(|_| plaintext)
+        });
+        let write = tokio::spawn(async move { writer.write_all(PLAINTEXT).await });
+        tokio::task::yield_now().await;
+
+        let mut server = AeadStream::new(server, cipher, KEY.into()).unwrap();
+        server.write_all(PLAINTEXT).await.unwrap();
+
+        write.await.unwrap().unwrap();
+        assert_eq!(&read.await.unwrap().unwrap(), PLAINTEXT);
+
+        let mut plaintext = [0u8; PLAINTEXT.len()];
+        server.read_exact(&mut plaintext).await.unwrap();
+        assert_eq!(&plaintext, PLAINTEXT);
+    }
+
+    #[tokio::test]
+    async fn writes_span_chunks() {
+        let (client, server) = duplex(1024);
+        let mut client = AeadStream::new(client, Cipher::ChaCha20Poly1305, KEY.into()).unwrap();
+        let mut server = AeadStream::new(server, Cipher::ChaCha20Poly1305, KEY.into()).unwrap();
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let write = tokio::spawn(async move {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        let mut read = Vec::new();
+        server.read_to_end(&mut read).await.unwrap();
+        write.await.unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[tokio::test]
+    async fn tampered_chunks_fail_authentication() {
+        for (cipher, _, chunks) in VECTORS {
+            let wire = sealed(cipher, chunks);
+            let length_at = cipher.key_size();
+            let payload_at = length_at + 2 + TAG_SIZE;
+
+            for (part, at) in [("salt", 0), ("length", length_at), ("payload", payload_at)] {
+                let mut tampered = wire.clone();
+                tampered[at + 1] ^= 0x01;
+
+                let error = open(cipher, &tampered).await.unwrap_err();
+                assert_eq!(
+                    error.kind(),
+                    ErrorKind::InvalidData,
+                    "{:?} {}",
+                    cipher,
+                    part
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_chunks_fail() {
+        for (cipher, _, chunks) in VECTORS {
+            let wire = sealed(cipher, chunks);
+            let length_at = cipher.key_size();
+            let payload_at = length_at + 2 + TAG_SIZE;
+
+            for (part, len) in [
+                ("salt", length_at - 1),
+                ("length", length_at + 1),
+                ("payload", payload_at + 1),
+                ("tag", wire.len() - 1),
+            ] {
+                let error = open(cipher, &wire[..len]).await.unwrap_err();
+                assert_eq!(
+                    error.kind(),
+                    ErrorKind::UnexpectedEof,
+                    "{:?} {}",
+                    cipher,
+                    part
+                );
+            }
+
+            // Between chunks the stream may end cleanly.
+            assert!(open(cipher, &wire[..length_at]).await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_lengths_are_rejected() {
+        for (cipher, _, _) in VECTORS {
+            for length in [0, MAX_PAYLOAD_SIZE as u16 + 1] {
+                let salt = [sender_salt(cipher), receiver_salt(cipher)].concat();
+                let mut session = Session::new(cipher, KEY, &salt);
+                let wire = [sender_salt(cipher), session.seal(&length.to_be_bytes())].concat();
+
+                let error = open(cipher, &wire).await.unwrap_err();
+                assert_eq!(
+                    error.kind(),
+                    ErrorKind::InvalidData,
+                    "{:?} {}",
+                    cipher,
+                    length
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll, Waker};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Seals and opens the frames of a [`FramedStream`].
+pub(crate) trait Codec {
+    /// Size of what the peer sends ahead of its first frame, e.g. a salt.
+    /// Nothing is sealed before it is opened, so frames may be keyed on it.
+    fn preamble_size(&self) -> usize {
+        0
+    }
+
+    fn open_preamble(&mut self, _preamble: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Size of the header preceding every frame.
+    fn header_size(&self) -> usize;
+
+    /// Size of the frame body following `header`.
+    fn open_header(&mut self, header: &[u8]) -> Result<usize>;
+
+    fn open_body(&mut self, body: &[u8]) -> Result<Bytes>;
+
+    /// Most plaintext bytes sealed into one frame.
+    fn max_payload_size(&self) -> usize;
+
+    /// Appends the header and body of the frame carrying `plaintext`.
+    fn seal(&mut self, plaintext: &[u8], frame: &mut BytesMut) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    Preamble,
+    Header,
+    Body(usize),
+}
+
+/// A stream sent as frames sealed by its [`Codec`]. Every write is sent as
+/// one or more frames, a read fails with the codec's error on the first frame
+/// that does not open.
+///
+/// Until the peer's preamble is read, writes read it themselves. Whichever
+/// side is left waiting for it is woken once the other opened it, as both
+/// register on the inner stream and only one of them is kept.
+pub(crate) struct FramedStream<S, C> {
+    inner: S,
+    codec: C,
+    read_state: ReadState,
+    read_buffer: Vec<u8>,
+    read_filled: usize,
+    plaintext: Bytes,
+    pending: BytesMut,
+    preamble_wakers: [Option<Waker>; 2],
+}
+
+impl<S, C> FramedStream<S, C>
+where
+    C: Codec,
+{
+    /// A stream sending `preamble` ahead of its first frame.
+    pub(crate) fn new(inner: S, codec: C, preamble: &[u8]) -> Self {
+        let read_state = match codec.preamble_size() {
+            0 => ReadState::Header,
+            _ => ReadState::Preamble,
+        };
+
+        Self {
+            inner,
+            codec,
+            read_state,
+            read_buffer: Vec::new(),
+            read_filled: 0,
+            plaintext: Bytes::new(),
+            pending: BytesMut::from(preamble),
+            preamble_wakers: [None, None],
+        }
+    }
+
+    #[cfg(feature = "noise")]
+    pub(crate) fn codec(&self) -> &C {
+        &self.codec
+    }
+}
+
+impl<S, C> FramedStream<S, C>
+where
+    S: AsyncRead + Unpin,
+    C: Codec,
+{
+    /// Reads the rest of what the read state needs, `false` if the stream
+    /// ended cleanly before it.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool>> {
+        let needed = match self.read_state {
+            ReadState::Preamble => self.codec.preamble_size(),
+            ReadState::Header => self.codec.header_size(),
+            ReadState::Body(len) => len,
+        };
+        self.read_buffer.resize(needed, 0);
+
+        while self.read_filled < needed {
+            let mut read_buf = ReadBuf::new(&mut self.read_buffer[self.read_filled..]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read_buf))?;
+
+            let read = read_buf.filled().len();
+            if read == 0 {
+                // A clean end only falls between frames.
+                let between_frames =
+                    self.read_filled == 0 && !matches!(self.read_state, ReadState::Body(_));
+
+                return match between_frames {
+                    true => Poll::Ready(Ok(false)),
+                    false => Poll::Ready(Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "sealed stream ended within a frame",
+                    ))),
+                };
+            }
+            self.read_filled += read;
+        }
+        self.read_filled = 0;
+
+        Poll::Ready(Ok(true))
+    }
+
+    /// Reads and opens the peer's preamble, `side` is 0 for reads and 1 for
+    /// writes.
+    fn poll_preamble(&mut self, cx: &mut Context<'_>, side: usize) -> Poll<Result<bool>> {
+        let filled = match self.poll_fill(cx) {
+            Poll::Ready(filled) => filled?,
+            Poll::Pending => {
+                self.preamble_wakers[side] = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+
+        if filled {
+            self.codec.open_preamble(&self.read_buffer)?;
+            self.read_state = ReadState::Header;
+        }
+
+        if let Some(waker) = self.preamble_wakers[1 - side].take() {
+            waker.wake();
+        }
+
+        Poll::Ready(Ok(filled))
+    }
+}
+
+impl<S, C> FramedStream<S, C>
+where
+    S: AsyncWrite + Unpin,
+{
+    /// Writes out the frames sealed so far.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while self.pending.has_remaining() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S, C> AsyncRead for FramedStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = &mut *self;
+
+        // Sealed frames waiting to go out must not be held up by a reader
+        // waiting for the answer to them. Errors surface on the next write.
+        let _ = this.poll_drain(cx);
+
+        loop {
+            if this.plaintext.has_remaining() {
+                let len = this.plaintext.len().min(buf.remaining());
+                buf.put_slice(&this.plaintext[..len]);
+                this.plaintext.advance(len);
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.read_state == ReadState::Preamble {
+                match ready!(this.poll_preamble(cx, 0))? {
+                    true => continue,
+                    false => return Poll::Ready(Ok(())),
+                }
+            }
+
+            if !ready!(this.poll_fill(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+
+            this.read_state = match this.read_state {
+                ReadState::Body(_) => {
+                    this.plaintext = this.codec.open_body(&this.read_buffer)?;
+                    ReadState::Header
+                }
+                _ => ReadState::Body(this.codec.open_header(&this.read_buffer)?),
+            };
+        }
+    }
+}
+
+impl<S, C> AsyncWrite for FramedStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if this.read_state == ReadState::Preamble && !ready!(this.poll_preamble(cx, 1))? {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "sealed stream ended before its preamble",
+            )));
+        }
+
+        let len = buf.len().min(this.codec.max_payload_size());
+        this.codec.seal(&buf[..len], &mut this.pending)?;
+
+        // Send right away like a socket would, a pending write is driven by
+        // the next read, write or flush.
+        if let Poll::Ready(Err(error)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(error));
+        }
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
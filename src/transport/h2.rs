@@ -0,0 +1,492 @@
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, Bytes};
+use h2::client::SendRequest;
+use h2::ext::Protocol;
+use h2::server::{Connection, SendResponse};
+use h2::{Reason, RecvStream, SendStream};
+use http::{Method, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::JoinSet;
+
+use super::handshake::Handshakes;
+use crate::Provider;
+
+const DEFAULT_PATH: &str = "/";
+
+/// Value of the `:protocol` pseudo-header of every tunnel request.
+const PROTOCOL: &str = "quicos";
+
+/// An HTTP/2 stream opened with an extended `CONNECT` ([RFC 8441]), one per
+/// tunnel. Received data is released back to the peer's flow-control window
+/// as soon as it is read.
+///
+/// [RFC 8441]: https://www.rfc-editor.org/rfc/rfc8441
+pub struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    buffer: Bytes,
+}
+
+impl H2Stream {
+    pub fn new(send: SendStream<Bytes>, recv: RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            buffer: Bytes::new(),
+        }
+    }
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        while !self.buffer.has_remaining() {
+            match ready!(self.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    self.recv
+                        .flow_control()
+                        .release_capacity(data.len())
+                        .map_err(into_io_error)?;
+                    self.buffer = data;
+                }
+                Some(Err(error)) if error.reason() == Some(Reason::NO_ERROR) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer[..len]);
+        self.buffer.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        self.send.reserve_capacity(buf.len());
+
+        loop {
+            match ready!(self.send.poll_capacity(cx)) {
+                Some(Ok(0)) => continue,
+                Some(Ok(capacity)) => {
+                    let len = capacity.min(buf.len());
+                    self.send
+                        .send_data(Bytes::copy_from_slice(&buf[..len]), false)
+                        .map_err(into_io_error)?;
+
+                    return Poll::Ready(Ok(len));
+                }
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+                None => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::BrokenPipe,
+                        "http/2 stream closed",
+                    )))
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.send
+            .send_data(Bytes::new(), true)
+            .map_err(into_io_error)?;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn into_io_error(error: h2::Error) -> Error {
+    if error.is_io() {
+        return error.into_io().expect("error must be an io error");
+    }
+
+    match error.is_reset() {
+        true => Error::new(ErrorKind::ConnectionReset, error),
+        false => Error::other(error),
+    }
+}
+
+type Accepted<S> = (
+    Connection<S, Bytes>,
+    Option<std::result::Result<(Request<RecvStream>, SendResponse<Bytes>), h2::Error>>,
+);
+
+/// Accept side for a `Server`, runs HTTP/2 over every stream yielded by the
+/// wrapped provider and yields each tunnel opened on those connections.
+/// Requests that are not an extended `CONNECT` to the configured path are
+/// refused.
+pub struct H2AcceptProvider<P, S> {
+    inner: P,
+    path: String,
+    handshakes: Handshakes<Connection<S, Bytes>>,
+    streams: JoinSet<Accepted<S>>,
+}
+
+impl<P, S> H2AcceptProvider<P, S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            path: DEFAULT_PATH.to_string(),
+            handshakes: Handshakes::new(),
+            streams: JoinSet::new(),
+        }
+    }
+
+    pub fn with_path<T: Into<String>>(mut self, path: T) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Keeps the connection driven while waiting for its next request.
+    fn accept(&mut self, mut connection: Connection<S, Bytes>) {
+        self.streams.spawn(async move {
+            let accepted = connection.accept().await;
+            (connection, accepted)
+        });
+    }
+
+    fn open(
+        &self,
+        request: Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) -> Option<H2Stream> {
+        let status = if request.method() != Method::CONNECT
+            || request
+                .extensions()
+                .get::<Protocol>()
+                .is_none_or(|protocol| protocol.as_str() != PROTOCOL)
+        {
+            StatusCode::BAD_REQUEST
+        } else if request.uri().path() != self.path {
+            StatusCode::NOT_FOUND
+        } else {
+            StatusCode::OK
+        };
+
+        let response = Response::builder().status(status).body(()).ok()?;
+        let send = respond
+            .send_response(response, status != StatusCode::OK)
+            .ok()?;
+        if status != StatusCode::OK {
+            return None;
+        }
+
+        Some(H2Stream::new(send, request.into_body()))
+    }
+}
+
+impl<P, S> Provider<H2Stream> for H2AcceptProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<H2Stream> {
+        loop {
+            if self.handshakes.is_done() && self.streams.is_empty() {
+                return None;
+            }
+
+            let handshaking = !self.handshakes.is_done();
+            let handshake = |stream| async move {
+                h2::server::Builder::new()
+                    .enable_connect_protocol()
+                    .handshake(stream)
+                    .await
+                    .map_err(into_io_error)
+            };
+
+            tokio::select! {
+                Some(connection) = self.handshakes.next(&mut self.inner, handshake), if handshaking => {
+                    self.accept(connection);
+                }
+
+                Some(joined) = self.streams.join_next(), if !self.streams.is_empty() => {
+                    let Ok((connection, Some(Ok((request, respond))))) = joined else {
+                        continue;
+                    };
+
+                    self.accept(connection);
+                    if let Some(stream) = self.open(request, respond) {
+                        return Some(stream);
+                    }
+                }
+
+                else => continue,
+            }
+        }
+    }
+}
+
+/// Connect side for a `Client`, opens a new HTTP/2 stream per fetch with an
+/// extended `CONNECT` to `host` and the configured path. Streams share one
+/// connection over a stream fetched from the wrapped provider, which is
+/// re-established once it is lost.
+pub struct H2ConnectProvider<P, S> {
+    inner: P,
+    host: String,
+    path: String,
+    connection: Option<SendRequest<Bytes>>,
+    _stream: PhantomData<S>,
+}
+
+impl<P, S> H2ConnectProvider<P, S> {
+    pub fn new<H: Into<String>>(inner: P, host: H) -> Self {
+        Self {
+            inner,
+            host: host.into(),
+            path: DEFAULT_PATH.to_string(),
+            connection: None,
+            _stream: PhantomData,
+        }
+    }
+
+    pub fn with_path<T: Into<String>>(mut self, path: T) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl<P, S> H2ConnectProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn connection(&mut self) -> Result<SendRequest<Bytes>> {
+        if let Some(connection) = &self.connection {
+            if let Ok(connection) = connection.clone().ready().await {
+                return Ok(connection);
+            }
+        }
+
+        let stream = self
+            .inner
+            .fetch()
+            .await
+            .ok_or_else(|| Error::new(ErrorKind::NotConnected, "no stream to connect over"))?;
+
+        let (connection, driver) = h2::client::handshake(stream).await.map_err(into_io_error)?;
+        tokio::spawn(driver);
+
+        self.connection = Some(connection.clone());
+        connection.ready().await.map_err(into_io_error)
+    }
+
+    async fn open(&mut self) -> Result<H2Stream> {
+        let mut connection = self.connection().await?;
+
+        let request = Request::builder()
+            .method(Method::CONNECT)
+            .uri(format!("https://{}{}", self.host, self.path))
+            .extension(Protocol::from_static(PROTOCOL))
+            .body(())
+            .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+
+        let (response, send) = connection
+            .send_request(request, false)
+            .map_err(into_io_error)?;
+        let response = response.await.map_err(into_io_error)?;
+
+        if response.status() != StatusCode::OK {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("http/2 connect refused with {}", response.status()),
+            ));
+        }
+
+        Ok(H2Stream::new(send, response.into_body()))
+    }
+}
+
+impl<P, S> Provider<H2Stream> for H2ConnectProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<H2Stream> {
+        // A connection can die between the liveness check and opening the
+        // stream, retry once on a fresh one.
+        for _ in 0..2 {
+            match self.open().await {
+                Ok(stream) => return Some(stream),
+                Err(error) if error.kind() == ErrorKind::ConnectionRefused => return None,
+                Err(_) => self.connection = None,
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::transport::tcp::{TcpAcceptProvider, TcpConnectProvider};
+
+    const PATH: &str = "/tunnel";
+
+    /// Counts the streams fetched from the wrapped provider.
+    struct Counting<P>(P, Arc<AtomicUsize>);
+
+    impl<P: Provider<TcpStream> + Send> Provider<TcpStream> for Counting<P> {
+        async fn fetch(&mut self) -> Option<TcpStream> {
+            let stream = self.0.fetch().await?;
+            self.1.fetch_add(1, Ordering::Relaxed);
+            Some(stream)
+        }
+    }
+
+    /// A server on `address` echoing every tunnel, counting its connections.
+    async fn serve(address: SocketAddr) -> (JoinHandle<()>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind(address).await.unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let inner = Counting(TcpAcceptProvider::new(listener), connections.clone());
+        let mut accept = H2AcceptProvider::new(inner).with_path(PATH);
+        let server = tokio::spawn(async move {
+            while let Some(stream) = accept.fetch().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = tokio::io::split(stream);
+                    tokio::io::copy(&mut reader, &mut writer).await?;
+                    writer.shutdown().await
+                });
+            }
+        });
+
+        (server, connections)
+    }
+
+    async fn loopback() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn connect(address: SocketAddr) -> H2ConnectProvider<TcpConnectProvider, TcpStream> {
+        H2ConnectProvider::new(TcpConnectProvider::new(address), "tunnel.example").with_path(PATH)
+    }
+
+    /// Reads while writing, the echo would stall on a full window otherwise.
+    async fn echo(stream: &mut H2Stream, message: &[u8]) -> Vec<u8> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut echoed = Vec::new();
+
+        let send = async {
+            writer.write_all(message).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let (_, read) = tokio::join!(send, reader.read_to_end(&mut echoed));
+        read.unwrap();
+
+        echoed
+    }
+
+    #[tokio::test]
+    async fn connect_round_trip() {
+        let address = loopback().await;
+        let (_server, _) = serve(address).await;
+
+        let mut stream = connect(address).fetch().await.unwrap();
+        let message = vec![0x5A; 256 * 1024];
+        assert_eq!(echo(&mut stream, &message).await, message);
+    }
+
+    #[tokio::test]
+    async fn streams_share_one_connection() {
+        let address = loopback().await;
+        let (_server, connections) = serve(address).await;
+        let mut provider = connect(address);
+
+        let mut first = provider.fetch().await.unwrap();
+        let mut second = provider.fetch().await.unwrap();
+        let mut third = provider.fetch().await.unwrap();
+
+        assert_eq!(echo(&mut second, b"second").await, b"second");
+        assert_eq!(echo(&mut first, b"first").await, b"first");
+        assert_eq!(echo(&mut third, b"third").await, b"third");
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_connection_closes() {
+        let address = loopback().await;
+        let (server, _) = serve(address).await;
+        let mut provider = connect(address);
+
+        let mut stream = provider.fetch().await.unwrap();
+        assert_eq!(echo(&mut stream, b"before").await, b"before");
+
+        // Dropping the server drops its connections along with the listener.
+        server.abort();
+        let _ = server.await;
+        let (_server, connections) = serve(address).await;
+
+        let mut stream = provider.fetch().await.unwrap();
+        assert_eq!(echo(&mut stream, b"after").await, b"after");
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn requests_other_than_the_tunnel_are_refused() {
+        let address = loopback().await;
+        let (_server, _) = serve(address).await;
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut client, driver) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(driver);
+
+        let plain_connect = Request::builder()
+            .method(Method::CONNECT)
+            .uri("tunnel.example:443")
+            .body(())
+            .unwrap();
+        let other_path = Request::builder()
+            .method(Method::CONNECT)
+            .uri("https://tunnel.example/other")
+            .extension(Protocol::from_static(PROTOCOL))
+            .body(())
+            .unwrap();
+
+        for (request, status) in [
+            (plain_connect, StatusCode::BAD_REQUEST),
+            (other_path, StatusCode::NOT_FOUND),
+        ] {
+            client = client.ready().await.unwrap();
+            let (response, _) = client.send_request(request, false).unwrap();
+            assert_eq!(response.await.unwrap().status(), status);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+use crate::Provider;
+
+/// How long a client has to complete its handshake before it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshakes of an accept provider, run concurrently so a slow client does
+/// not hold up the others.
+pub(crate) struct Handshakes<T> {
+    pending: JoinSet<Result<T>>,
+    exhausted: bool,
+}
+
+impl<T> Handshakes<T>
+where
+    T: Send + 'static,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: JoinSet::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Whether the wrapped provider is exhausted and every handshake is done.
+    pub(crate) fn is_done(&self) -> bool {
+        self.exhausted && self.pending.is_empty()
+    }
+
+    /// Starts `handshake` on every stream fetched from `inner` until one of
+    /// them completes, failed or timed out handshakes are dropped. Returns
+    /// `None` once [`is_done`](Self::is_done).
+    ///
+    /// Cancel safe as long as `inner`'s fetch is.
+    pub(crate) async fn next<P, S, F, H>(&mut self, inner: &mut P, mut handshake: F) -> Option<T>
+    where
+        P: Provider<S>,
+        F: FnMut(S) -> H,
+        H: Future<Output = Result<T>> + Send + 'static,
+    {
+        loop {
+            if self.is_done() {
+                return None;
+            }
+
+            tokio::select! {
+                stream = inner.fetch(), if !self.exhausted => {
+                    let Some(stream) = stream else {
+                        self.exhausted = true;
+                        continue;
+                    };
+
+                    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream));
+                    self.pending.spawn(async move {
+                        handshake.await.unwrap_or_else(|_| {
+                            Err(Error::new(ErrorKind::TimedOut, "handshake timed out"))
+                        })
+                    });
+                }
+
+                Some(joined) = self.pending.join_next(), if !self.pending.is_empty() => {
+                    if let Ok(Ok(accepted)) = joined {
+                        return Some(accepted);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use super::*;
+
+    struct Streams(Vec<u32>);
+
+    impl Provider<u32> for Streams {
+        async fn fetch(&mut self) -> Option<u32> {
+            self.0.pop()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_handshakes_time_out() {
+        let mut handshakes = Handshakes::new();
+        let mut streams = Streams(vec![2, 1]);
+
+        let accepted = handshakes
+            .next(&mut streams, |stream| async move {
+                if stream == 1 {
+                    pending::<()>().await;
+                }
+                Ok(stream)
+            })
+            .await;
+        assert_eq!(accepted, Some(2));
+
+        let started = tokio::time::Instant::now();
+        let accepted = handshakes.next(&mut streams, |_| async { Ok(0) }).await;
+        assert_eq!(accepted, None);
+        assert_eq!(started.elapsed(), HANDSHAKE_TIMEOUT);
+        assert!(handshakes.is_done());
+    }
+}
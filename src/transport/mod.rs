@@ -0,0 +1,107 @@
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+
+#[cfg(feature = "aead")]
+pub mod aead;
+#[cfg(any(feature = "aead", feature = "noise"))]
+mod frame;
+#[cfg(feature = "h2")]
+pub mod h2;
+pub(crate) mod handshake;
+pub mod mux;
+#[cfg(feature = "noise")]
+pub mod noise;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+/// How long an accept loop pauses after an error that outlasts the
+/// connection it happened to.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Waits before the next accept after `error`. Errors of the one connection
+/// being accepted are passed over right away, others such as running out of
+/// file descriptors persist until connections are closed, so retrying them
+/// at once would only spin.
+pub(crate) async fn backoff_accept(error: &Error) {
+    match error.kind() {
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::Interrupted => {}
+        _ => {
+            log::warn!("accept failed, retrying in {:?}: {}", ACCEPT_BACKOFF, error);
+            tokio::time::sleep(ACCEPT_BACKOFF).await;
+        }
+    }
+}
+
+/// Options applied to every stream accepted or connected by the TCP and Unix
+/// domain socket providers. Unset options keep the system defaults.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disables Nagle's algorithm, TCP only.
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Idle time before keepalive probes are sent, TCP only.
+    pub fn with_keepalive(mut self, time: Duration) -> Self {
+        self.keepalive = Some(time);
+        self
+    }
+
+    pub fn with_send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    pub fn with_recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    pub(crate) fn apply_tcp(&self, socket: SockRef<'_>) -> Result<()> {
+        if self.nodelay {
+            socket.set_tcp_nodelay(true)?;
+        }
+
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+
+        self.apply(socket)
+    }
+
+    pub(crate) fn apply(&self, socket: SockRef<'_>) -> Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+}
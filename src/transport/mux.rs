@@ -0,0 +1,834 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::Provider;
+
+#[rustfmt::skip]
+mod consts_mux {
+    pub const TYPE_OPEN:          u8 = 0x01;
+    pub const TYPE_DATA:          u8 = 0x02;
+    pub const TYPE_WINDOW_UPDATE: u8 = 0x03;
+    pub const TYPE_CLOSE:         u8 = 0x04;
+    pub const TYPE_PING:          u8 = 0x05;
+
+    pub const FLAG_NONE:          u8 = 0x00;
+    pub const FLAG_RESET:         u8 = 0x01;
+    pub const FLAG_ACK:           u8 = 0x02;
+}
+
+/// Window every stream starts with in both directions, before any update.
+const INITIAL_WINDOW: u32 = 256 * 1024;
+
+const MAX_DATA_SIZE: usize = 16 * 1024;
+
+/// Options of the sessions run by the mux providers.
+#[derive(Debug, Clone)]
+pub struct MuxOptions {
+    window: u32,
+    keepalive: Option<Duration>,
+}
+
+impl Default for MuxOptions {
+    fn default() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            keepalive: None,
+        }
+    }
+}
+
+impl MuxOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes a peer may send on a stream before it is read, at least 256 KiB.
+    pub fn with_window(mut self, window: u32) -> Self {
+        self.window = window.max(INITIAL_WINDOW);
+        self
+    }
+
+    /// Pings the peer every `interval`, the session is closed when a ping is
+    /// still unanswered by the next one.
+    pub fn with_keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+}
+
+/// A logical stream of a session multiplexed over a single byte stream.
+///
+/// ## Frames
+/// ```text
+///          +------+-------+--------+--------+----------+
+///          | TYPE | FLAGS | STREAM | LENGTH |   DATA   |
+///          +------+-------+--------+--------+----------+
+///          |  1   |   1   |   4    |   4    | Variable |
+///          +------+-------+--------+--------+----------+
+/// ```
+///
+/// Only `DATA` frames carry `LENGTH` bytes of payload. A `WINDOW_UPDATE`
+/// grants `LENGTH` more bytes to the sender of the stream, a `PING` carries
+/// an opaque `LENGTH` echoed back with the `ACK` flag. `CLOSE` ends the
+/// sending half of a stream, or the whole stream with the `RESET` flag.
+///
+/// Streams are opened by the connect side with odd ids and need no answer,
+/// data may follow the `OPEN` right away.
+pub struct MuxStream {
+    id: u32,
+    session: Arc<Session>,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let mut state = self.session.state();
+        let closed = state.closed;
+
+        let Some(stream) = state.streams.get_mut(&self.id) else {
+            return Poll::Ready(Err(reset_error()));
+        };
+
+        if !stream.buffer.is_empty() {
+            let len = stream.buffer.len().min(buf.remaining());
+            buf.put_slice(&stream.buffer[..len]);
+            stream.buffer.advance(len);
+
+            // Hand the window back in batches rather than per read.
+            stream.consumed += len as u32;
+            if stream.consumed >= self.session.window / 2 && !stream.recv_closed {
+                self.session.send(frame(
+                    consts_mux::TYPE_WINDOW_UPDATE,
+                    consts_mux::FLAG_NONE,
+                    self.id,
+                    stream.consumed,
+                ));
+                stream.recv_window += stream.consumed;
+                stream.consumed = 0;
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        if stream.recv_closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        if stream.reset || closed {
+            return Poll::Ready(Err(reset_error()));
+        }
+
+        stream.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let mut state = self.session.state();
+        let closed = state.closed;
+
+        let Some(stream) = state.streams.get_mut(&self.id) else {
+            return Poll::Ready(Err(reset_error()));
+        };
+
+        if stream.reset || closed {
+            return Poll::Ready(Err(reset_error()));
+        }
+
+        if stream.send_closed {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "mux stream already shut down",
+            )));
+        }
+
+        if stream.send_window == 0 {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = buf
+            .len()
+            .min(stream.send_window as usize)
+            .min(MAX_DATA_SIZE);
+        stream.send_window -= len as u32;
+
+        let mut data = BytesMut::from(frame(
+            consts_mux::TYPE_DATA,
+            consts_mux::FLAG_NONE,
+            self.id,
+            len as u32,
+        ));
+        data.extend_from_slice(&buf[..len]);
+        self.session.send(data.freeze());
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut state = self.session.state();
+        let closed = state.closed;
+
+        if let Some(stream) = state.streams.get_mut(&self.id) {
+            if !stream.send_closed && !stream.reset && !closed {
+                self.session.send(frame(
+                    consts_mux::TYPE_CLOSE,
+                    consts_mux::FLAG_NONE,
+                    self.id,
+                    0,
+                ));
+                stream.send_closed = true;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut state = self.session.state();
+        let closed = state.closed;
+
+        let Some(stream) = state.streams.remove(&self.id) else {
+            return;
+        };
+
+        // The peer is told to stop unless both halves already ended cleanly.
+        let finished = stream.send_closed && stream.recv_closed;
+        if !(closed || stream.reset || finished) {
+            self.session.send(frame(
+                consts_mux::TYPE_CLOSE,
+                consts_mux::FLAG_RESET,
+                self.id,
+                0,
+            ));
+        }
+    }
+}
+
+fn reset_error() -> Error {
+    Error::new(ErrorKind::ConnectionReset, "mux stream reset")
+}
+
+fn frame(kind: u8, flags: u8, id: u32, length: u32) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(10);
+
+    bytes.put_u8(kind);
+    bytes.put_u8(flags);
+    bytes.put_u32(id);
+    bytes.put_u32(length);
+
+    bytes.freeze()
+}
+
+struct StreamState {
+    buffer: BytesMut,
+    recv_window: u32,
+    consumed: u32,
+    send_window: u32,
+    recv_closed: bool,
+    send_closed: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new(window: u32) -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            recv_window: window,
+            consumed: 0,
+            send_window: INITIAL_WINDOW,
+            recv_closed: false,
+            send_closed: false,
+            reset: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct State {
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+    closed: bool,
+    ping_outstanding: bool,
+}
+
+/// One multiplexed connection, driven by a task that owns the byte stream.
+/// The task stops once the peer goes away, or on the connect side once the
+/// session and all of its streams are dropped.
+struct Session {
+    state: Mutex<State>,
+    frames: UnboundedSender<Bytes>,
+    window: u32,
+}
+
+impl Session {
+    fn spawn<S>(
+        stream: S,
+        options: &MuxOptions,
+        accept: Option<UnboundedSender<MuxStream>>,
+    ) -> Arc<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (frames, outgoing) = mpsc::unbounded_channel();
+
+        let session = Arc::new(Self {
+            state: Mutex::new(State {
+                streams: HashMap::new(),
+                next_id: 1,
+                closed: false,
+                ping_outstanding: false,
+            }),
+            frames,
+            window: options.window,
+        });
+
+        // The accept side has no handle of its own to keep the session alive.
+        let keep = accept.as_ref().map(|_| session.clone());
+        let weak = Arc::downgrade(&session);
+        let keepalive = options.keepalive;
+
+        tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(stream);
+
+            let _ = tokio::select! {
+                result = read_frames(reader, &weak, accept) => result,
+                result = write_frames(writer, outgoing) => result,
+                result = ping(&weak, keepalive) => result,
+            };
+
+            if let Some(session) = weak.upgrade() {
+                session.close();
+            }
+            drop(keep);
+        });
+
+        session
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn send(&self, frame: Bytes) {
+        let _ = self.frames.send(frame);
+    }
+
+    /// Registers a stream with `id`, granting the peer the configured window.
+    fn insert(&self, state: &mut State, id: u32) {
+        state.streams.insert(id, StreamState::new(self.window));
+
+        if self.window > INITIAL_WINDOW {
+            self.send(frame(
+                consts_mux::TYPE_WINDOW_UPDATE,
+                consts_mux::FLAG_NONE,
+                id,
+                self.window - INITIAL_WINDOW,
+            ));
+        }
+    }
+
+    fn open(self: &Arc<Self>) -> Option<MuxStream> {
+        let mut state = self.state();
+        if state.closed {
+            return None;
+        }
+
+        let id = state.next_id;
+        state.next_id = id.checked_add(2)?;
+
+        self.send(frame(consts_mux::TYPE_OPEN, consts_mux::FLAG_NONE, id, 0));
+        self.insert(&mut state, id);
+
+        Some(MuxStream {
+            id,
+            session: self.clone(),
+        })
+    }
+
+    fn accept(self: &Arc<Self>, id: u32) -> Result<MuxStream> {
+        let mut state = self.state();
+        if state.streams.contains_key(&id) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("mux stream {} opened twice", id),
+            ));
+        }
+
+        self.insert(&mut state, id);
+
+        Ok(MuxStream {
+            id,
+            session: self.clone(),
+        })
+    }
+
+    fn receive(&self, kind: u8, flags: u8, id: u32, length: u32, payload: Bytes) -> Result<()> {
+        let mut state = self.state();
+
+        match kind {
+            consts_mux::TYPE_DATA => {
+                // Data may still arrive for a stream dropped on this side.
+                let Some(stream) = state.streams.get_mut(&id) else {
+                    return Ok(());
+                };
+
+                if length > stream.recv_window {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("mux stream {} exceeded its window", id),
+                    ));
+                }
+
+                stream.recv_window -= length;
+                stream.buffer.extend_from_slice(&payload);
+                if let Some(waker) = stream.read_waker.take() {
+                    waker.wake();
+                }
+            }
+
+            consts_mux::TYPE_WINDOW_UPDATE => {
+                if let Some(stream) = state.streams.get_mut(&id) {
+                    stream.send_window = stream.send_window.saturating_add(length);
+                    if let Some(waker) = stream.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+
+            consts_mux::TYPE_CLOSE => {
+                if let Some(stream) = state.streams.get_mut(&id) {
+                    match flags & consts_mux::FLAG_RESET != 0 {
+                        true => stream.reset = true,
+                        false => stream.recv_closed = true,
+                    }
+                    stream.wake();
+                }
+            }
+
+            consts_mux::TYPE_PING => match flags & consts_mux::FLAG_ACK != 0 {
+                true => state.ping_outstanding = false,
+                false => self.send(frame(
+                    consts_mux::TYPE_PING,
+                    consts_mux::FLAG_ACK,
+                    0,
+                    length,
+                )),
+            },
+
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported mux frame type {}", kind),
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn close(&self) {
+        let mut state = self.state();
+        state.closed = true;
+
+        for stream in state.streams.values_mut() {
+            stream.wake();
+        }
+    }
+}
+
+async fn read_frames<R>(
+    mut reader: R,
+    session: &Weak<Session>,
+    accept: Option<UnboundedSender<MuxStream>>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let kind = reader.read_u8().await?;
+        let flags = reader.read_u8().await?;
+        let id = reader.read_u32().await?;
+        let length = reader.read_u32().await?;
+
+        let mut payload = BytesMut::new();
+        if kind == consts_mux::TYPE_DATA {
+            if length as usize > MAX_DATA_SIZE {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("mux data frame of {} bytes too large", length),
+                ));
+            }
+
+            payload.resize(length as usize, 0);
+            reader.read_exact(&mut payload).await?;
+        }
+
+        let Some(session) = session.upgrade() else {
+            return Ok(());
+        };
+
+        if kind != consts_mux::TYPE_OPEN {
+            session.receive(kind, flags, id, length, payload.freeze())?;
+            continue;
+        }
+
+        match &accept {
+            // A stream the provider is gone for is dropped, which resets it.
+            Some(accept) => {
+                let _ = accept.send(session.accept(id)?);
+            }
+            None => session.send(frame(consts_mux::TYPE_CLOSE, consts_mux::FLAG_RESET, id, 0)),
+        }
+    }
+}
+
+async fn write_frames<W>(mut writer: W, mut frames: UnboundedReceiver<Bytes>) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(frame) = frames.recv().await {
+        writer.write_all(&frame).await?;
+
+        // Flush once per batch of queued frames.
+        while let Ok(frame) = frames.try_recv() {
+            writer.write_all(&frame).await?;
+        }
+        writer.flush().await?;
+    }
+
+    writer.shutdown().await
+}
+
+async fn ping(session: &Weak<Session>, interval: Option<Duration>) -> Result<()> {
+    let Some(interval) = interval else {
+        return std::future::pending().await;
+    };
+
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+    let mut sequence = 0u32;
+    loop {
+        ticker.tick().await;
+
+        let Some(session) = session.upgrade() else {
+            return Ok(());
+        };
+
+        let mut state = session.state();
+        if state.ping_outstanding {
+            return Err(Error::new(ErrorKind::TimedOut, "mux keepalive timed out"));
+        }
+
+        state.ping_outstanding = true;
+        session.send(frame(
+            consts_mux::TYPE_PING,
+            consts_mux::FLAG_NONE,
+            0,
+            sequence,
+        ));
+        sequence = sequence.wrapping_add(1);
+    }
+}
+
+/// Accept side for a `Server`, runs a session over every stream yielded by
+/// the wrapped provider and yields each logical stream the peers open.
+pub struct MuxAcceptProvider<P, S> {
+    inner: P,
+    options: MuxOptions,
+    sender: Option<UnboundedSender<MuxStream>>,
+    accepted: UnboundedReceiver<MuxStream>,
+    _stream: PhantomData<S>,
+}
+
+impl<P, S> MuxAcceptProvider<P, S> {
+    pub fn new(inner: P) -> Self {
+        let (sender, accepted) = mpsc::unbounded_channel();
+
+        Self {
+            inner,
+            options: MuxOptions::default(),
+            sender: Some(sender),
+            accepted,
+            _stream: PhantomData,
+        }
+    }
+
+    pub fn with_options(mut self, options: MuxOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl<P, S> Provider<MuxStream> for MuxAcceptProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<MuxStream> {
+        loop {
+            tokio::select! {
+                stream = self.inner.fetch(), if self.sender.is_some() => {
+                    match stream {
+                        Some(stream) => {
+                            Session::spawn(stream, &self.options, self.sender.clone());
+                        }
+                        // Sessions already running keep yielding streams.
+                        None => self.sender = None,
+                    }
+                }
+
+                stream = self.accepted.recv() => return stream,
+            }
+        }
+    }
+}
+
+/// Connect side for a `Client`, opens a new logical stream per fetch on one
+/// session over a stream fetched from the wrapped provider. The session is
+/// re-established once it is lost.
+pub struct MuxConnectProvider<P, S> {
+    inner: P,
+    options: MuxOptions,
+    session: Option<Arc<Session>>,
+    _stream: PhantomData<S>,
+}
+
+impl<P, S> MuxConnectProvider<P, S> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            options: MuxOptions::default(),
+            session: None,
+            _stream: PhantomData,
+        }
+    }
+
+    pub fn with_options(mut self, options: MuxOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl<P, S> Provider<MuxStream> for MuxConnectProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<MuxStream> {
+        if let Some(stream) = self.session.as_ref().and_then(Session::open) {
+            return Some(stream);
+        }
+
+        let stream = self.inner.fetch().await?;
+        let session = Session::spawn(stream, &self.options, None);
+        self.session = Some(session.clone());
+
+        session.open()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::time::{timeout, Instant};
+
+    use super::*;
+
+    /// Large enough for a whole window to be in flight.
+    const PIPE_SIZE: usize = 1024 * 1024;
+
+    /// Yields the streams sent through the channel.
+    struct Channel(UnboundedReceiver<DuplexStream>);
+
+    impl Provider<DuplexStream> for Channel {
+        async fn fetch(&mut self) -> Option<DuplexStream> {
+            self.0.recv().await
+        }
+    }
+
+    /// Opens an in-memory connection per fetch, sending the other end away.
+    struct Dialer(UnboundedSender<DuplexStream>);
+
+    impl Provider<DuplexStream> for Dialer {
+        async fn fetch(&mut self) -> Option<DuplexStream> {
+            let (local, remote) = duplex(PIPE_SIZE);
+            self.0.send(remote).ok()?;
+            Some(local)
+        }
+    }
+
+    type Connect = MuxConnectProvider<Dialer, DuplexStream>;
+    type Accept = MuxAcceptProvider<Channel, DuplexStream>;
+
+    fn providers(options: MuxOptions) -> (Connect, Accept) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        (
+            MuxConnectProvider::new(Dialer(sender)).with_options(options.clone()),
+            MuxAcceptProvider::new(Channel(receiver)).with_options(options),
+        )
+    }
+
+    async fn open(connect: &mut Connect, accept: &mut Accept) -> (MuxStream, MuxStream) {
+        let opened = connect.fetch().await.unwrap();
+        (opened, accept.fetch().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn open_and_round_trip() {
+        let (mut connect, mut accept) = providers(MuxOptions::new());
+        let (mut first, mut first_accepted) = open(&mut connect, &mut accept).await;
+        let (mut second, mut second_accepted) = open(&mut connect, &mut accept).await;
+
+        second.write_all(b"second").await.unwrap();
+        first.write_all(b"first").await.unwrap();
+
+        let mut received = [0u8; 6];
+        second_accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"second");
+        first_accepted.read_exact(&mut received[..5]).await.unwrap();
+        assert_eq!(&received[..5], b"first");
+
+        first_accepted.write_all(b"reply").await.unwrap();
+        first.read_exact(&mut received[..5]).await.unwrap();
+        assert_eq!(&received[..5], b"reply");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_stalls_until_the_window_is_updated() {
+        let (mut connect, mut accept) = providers(MuxOptions::new());
+        let (mut sender, mut receiver) = open(&mut connect, &mut accept).await;
+
+        let window = vec![0x5A; INITIAL_WINDOW as usize];
+        sender.write_all(&window).await.unwrap();
+
+        let stalled = timeout(Duration::from_secs(1), sender.write(b"more")).await;
+        assert!(stalled.is_err(), "window must be used up");
+
+        // Reading half the window hands it back to the sender.
+        let mut received = vec![0u8; INITIAL_WINDOW as usize / 2];
+        receiver.read_exact(&mut received).await.unwrap();
+
+        let written = timeout(Duration::from_secs(1), sender.write(b"more")).await;
+        assert_eq!(written.unwrap().unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn close_ends_one_half_and_reset_the_whole_stream() {
+        let (mut connect, mut accept) = providers(MuxOptions::new());
+
+        let (mut closed, mut closed_accepted) = open(&mut connect, &mut accept).await;
+        closed.write_all(b"last").await.unwrap();
+        closed.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        closed_accepted.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"last");
+
+        // The other half is still open after a close.
+        closed_accepted.write_all(b"answer").await.unwrap();
+        let mut answer = [0u8; 6];
+        closed.read_exact(&mut answer).await.unwrap();
+        assert_eq!(&answer, b"answer");
+
+        let (reset, mut reset_accepted) = open(&mut connect, &mut accept).await;
+        drop(reset);
+
+        let error = reset_accepted.read(&mut answer).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        let error = reset_accepted.write(b"late").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_timeout_ends_the_session() {
+        const INTERVAL: Duration = Duration::from_secs(10);
+
+        let (sender, mut remotes) = mpsc::unbounded_channel();
+        let options = MuxOptions::new().with_keepalive(INTERVAL);
+        let mut connect = MuxConnectProvider::new(Dialer(sender)).with_options(options);
+
+        // The peer is connected but never answers a ping.
+        let mut stream = connect.fetch().await.unwrap();
+        let _remote = remotes.recv().await.unwrap();
+
+        let started = Instant::now();
+        let error = stream.read(&mut [0u8; 1]).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(started.elapsed(), INTERVAL * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answered_keepalive_keeps_the_session() {
+        let options = MuxOptions::new().with_keepalive(Duration::from_secs(10));
+        let (mut connect, mut accept) = providers(options);
+        let (mut stream, mut accepted) = open(&mut connect, &mut accept).await;
+
+        let idle = timeout(Duration::from_secs(60), stream.read(&mut [0u8; 1])).await;
+        assert!(idle.is_err(), "session must outlive the idle minute");
+
+        accepted.write_all(b"alive").await.unwrap();
+        let mut received = [0u8; 5];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"alive");
+    }
+
+    #[tokio::test]
+    async fn connect_opens_a_new_session_after_the_old_one_dies() {
+        let (sender, mut remotes) = mpsc::unbounded_channel();
+        let mut connect = MuxConnectProvider::new(Dialer(sender));
+
+        let mut stream = connect.fetch().await.unwrap();
+        drop(remotes.recv().await.unwrap());
+
+        let error = stream.read(&mut [0u8; 1]).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+
+        let mut stream = connect.fetch().await.unwrap();
+        let remote = remotes.try_recv().expect("a new connection must be made");
+
+        let (sessions, receiver) = mpsc::unbounded_channel();
+        sessions.send(remote).unwrap();
+        let mut accept = MuxAcceptProvider::new(Channel(receiver));
+
+        stream.write_all(b"again").await.unwrap();
+        let mut received = [0u8; 5];
+        let mut accepted = accept.fetch().await.unwrap();
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"again");
+    }
+}
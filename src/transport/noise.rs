@@ -0,0 +1,597 @@
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use snow::params::NoiseParams;
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use super::frame::{Codec, FramedStream};
+use super::handshake::Handshakes;
+use crate::Provider;
+
+const PROLOGUE: &[u8] = b"quicos-noise";
+
+pub const KEY_SIZE: usize = 32;
+
+const TAG_SIZE: usize = 16;
+const MAX_MESSAGE_SIZE: usize = 0xFFFF;
+const MAX_PAYLOAD_SIZE: usize = MAX_MESSAGE_SIZE - TAG_SIZE;
+
+/// Both sides switch to a new key after this many messages per direction.
+#[cfg(not(test))]
+const REKEY_INTERVAL: u64 = 1 << 16;
+/// Small enough for tests to cross it in a debug build.
+#[cfg(test)]
+const REKEY_INTERVAL: u64 = 1 << 8;
+
+/// Handshake pattern, both sides must use the same one.
+///
+/// With `XX` the static keys are exchanged during the handshake, with `IK`
+/// the client must know the server's key up front and saves a round trip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NoisePattern {
+    #[default]
+    XX,
+    IK,
+}
+
+impl NoisePattern {
+    fn params(self) -> NoiseParams {
+        let params = match self {
+            Self::XX => "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+            Self::IK => "Noise_IK_25519_ChaChaPoly_BLAKE2s",
+        };
+
+        params.parse().expect("pattern must be supported")
+    }
+}
+
+/// A static Curve25519 key pair identifying a `Server` or `Client`.
+#[derive(Clone)]
+pub struct NoiseKeypair {
+    private: [u8; KEY_SIZE],
+    public: [u8; KEY_SIZE],
+}
+
+impl NoiseKeypair {
+    pub fn new(private: [u8; KEY_SIZE], public: [u8; KEY_SIZE]) -> Self {
+        Self { private, public }
+    }
+
+    pub fn generate() -> Result<Self> {
+        let keypair = Builder::new(NoisePattern::XX.params())
+            .generate_keypair()
+            .map_err(into_io_error)?;
+
+        let mut private = [0u8; KEY_SIZE];
+        let mut public = [0u8; KEY_SIZE];
+        private.copy_from_slice(&keypair.private);
+        public.copy_from_slice(&keypair.public);
+
+        Ok(Self { private, public })
+    }
+
+    pub fn private(&self) -> &[u8; KEY_SIZE] {
+        &self.private
+    }
+
+    pub fn public(&self) -> &[u8; KEY_SIZE] {
+        &self.public
+    }
+}
+
+fn into_io_error(error: snow::Error) -> Error {
+    match error {
+        snow::Error::Decrypt | snow::Error::Dh => Error::new(ErrorKind::InvalidData, error),
+        error => Error::other(error),
+    }
+}
+
+// ===== Handshake =====
+/// Runs the handshake to completion, every message preceded by its length.
+/// `verify` is asked about the remote static key as soon as it is known, so
+/// an unknown peer is dropped before anything else is sent to it.
+async fn handshake<S, F>(
+    mut stream: S,
+    mut state: HandshakeState,
+    verify: F,
+) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(&[u8]) -> bool,
+{
+    let mut message = vec![0u8; MAX_MESSAGE_SIZE];
+    let mut payload = vec![0u8; MAX_MESSAGE_SIZE];
+    let mut verified = false;
+
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state
+                .write_message(&[], &mut message)
+                .map_err(into_io_error)?;
+
+            stream.write_u16(len as u16).await?;
+            stream.write_all(&message[..len]).await?;
+            stream.flush().await?;
+        } else {
+            let len = stream.read_u16().await? as usize;
+            stream.read_exact(&mut message[..len]).await?;
+
+            state
+                .read_message(&message[..len], &mut payload)
+                .map_err(into_io_error)?;
+        }
+
+        if let (false, Some(remote)) = (verified, state.get_remote_static()) {
+            if !verify(remote) {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "noise peer key is not allowed",
+                ));
+            }
+            verified = true;
+        }
+    }
+
+    if !verified {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "noise peer sent no static key",
+        ));
+    }
+
+    let transport = state.into_transport_mode().map_err(into_io_error)?;
+    Ok(NoiseStream::new(stream, transport))
+}
+
+// ===== Stream =====
+/// Messages of a [`NoiseStream`], each preceded by its length.
+struct NoiseCodec {
+    transport: TransportState,
+    message: Vec<u8>,
+}
+
+impl Codec for NoiseCodec {
+    fn header_size(&self) -> usize {
+        2
+    }
+
+    fn open_header(&mut self, header: &[u8]) -> Result<usize> {
+        let length = u16::from_be_bytes([header[0], header[1]]) as usize;
+
+        if length <= TAG_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid noise message length {}", length),
+            ));
+        }
+
+        Ok(length)
+    }
+
+    fn open_body(&mut self, body: &[u8]) -> Result<Bytes> {
+        let len = self
+            .transport
+            .read_message(body, &mut self.message)
+            .map_err(into_io_error)?;
+
+        if self
+            .transport
+            .receiving_nonce()
+            .is_multiple_of(REKEY_INTERVAL)
+        {
+            self.transport.rekey_incoming();
+        }
+
+        Ok(Bytes::copy_from_slice(&self.message[..len]))
+    }
+
+    fn max_payload_size(&self) -> usize {
+        MAX_PAYLOAD_SIZE
+    }
+
+    fn seal(&mut self, plaintext: &[u8], frame: &mut BytesMut) -> Result<()> {
+        let sealed = self
+            .transport
+            .write_message(plaintext, &mut self.message)
+            .map_err(into_io_error)?;
+
+        if self
+            .transport
+            .sending_nonce()
+            .is_multiple_of(REKEY_INTERVAL)
+        {
+            self.transport.rekey_outgoing();
+        }
+
+        frame.extend_from_slice(&(sealed as u16).to_be_bytes());
+        frame.extend_from_slice(&self.message[..sealed]);
+        Ok(())
+    }
+}
+
+/// A stream secured with a completed Noise handshake.
+///
+/// ## Bytes
+/// ```text
+///          +--------+------------+-----+
+///          | LENGTH |  DATA+TAG  | ... |
+///          +--------+------------+-----+
+///          |   2    | LEN (max.  |     |
+///          |        |   65535)   |     |
+///          +--------+------------+-----+
+/// ```
+///
+/// Every write is sent as messages of at most 65519 bytes of data. Both
+/// directions are rekeyed every 65536 messages. Any message failing
+/// authentication fails the read with `InvalidData`.
+pub struct NoiseStream<S> {
+    inner: FramedStream<S, NoiseCodec>,
+}
+
+impl<S> NoiseStream<S> {
+    fn new(inner: S, transport: TransportState) -> Self {
+        let codec = NoiseCodec {
+            transport,
+            message: vec![0u8; MAX_MESSAGE_SIZE],
+        };
+
+        Self {
+            inner: FramedStream::new(inner, codec, &[]),
+        }
+    }
+
+    /// Static key of the peer, as authenticated by the handshake.
+    pub fn remote_public_key(&self) -> Option<&[u8]> {
+        self.inner.codec().transport.get_remote_static()
+    }
+}
+
+impl<S> AsyncRead for NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// ===== Server =====
+/// Accept side for a `Server`, runs the Noise handshake as responder on
+/// every stream yielded by the wrapped provider. Only clients whose public
+/// key was added are accepted, unless any client is allowed with
+/// [`with_any_client`](Self::with_any_client).
+pub struct NoiseAcceptProvider<P, S> {
+    inner: P,
+    keypair: NoiseKeypair,
+    pattern: NoisePattern,
+    clients: Arc<Vec<[u8; KEY_SIZE]>>,
+    any_client: bool,
+    handshakes: Handshakes<NoiseStream<S>>,
+}
+
+impl<P, S> NoiseAcceptProvider<P, S>
+where
+    S: Send + 'static,
+{
+    pub fn new(inner: P, keypair: NoiseKeypair) -> Self {
+        Self {
+            inner,
+            keypair,
+            pattern: NoisePattern::default(),
+            clients: Arc::new(Vec::new()),
+            any_client: false,
+            handshakes: Handshakes::new(),
+        }
+    }
+
+    pub fn with_pattern(mut self, pattern: NoisePattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    /// Allows a client's public key, may be called repeatedly.
+    pub fn with_client(mut self, public: [u8; KEY_SIZE]) -> Self {
+        Arc::make_mut(&mut self.clients).push(public);
+        self
+    }
+
+    /// Accepts every client, whatever its key, e.g. when clients are
+    /// authenticated by other means.
+    pub fn with_any_client(mut self) -> Self {
+        self.any_client = true;
+        self
+    }
+}
+
+fn responder(pattern: NoisePattern, keypair: &NoiseKeypair) -> Result<HandshakeState> {
+    Builder::new(pattern.params())
+        .local_private_key(&keypair.private)
+        .and_then(|builder| builder.prologue(PROLOGUE))
+        .and_then(|builder| builder.build_responder())
+        .map_err(into_io_error)
+}
+
+impl<P, S> Provider<NoiseStream<S>> for NoiseAcceptProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<NoiseStream<S>> {
+        let (keypair, pattern) = (&self.keypair, self.pattern);
+        let (clients, any_client) = (&self.clients, self.any_client);
+
+        self.handshakes
+            .next(&mut self.inner, |stream| {
+                let state = responder(pattern, keypair);
+                let clients = clients.clone();
+
+                async move {
+                    handshake(stream, state?, move |remote| {
+                        any_client || clients.iter().any(|client| client[..] == *remote)
+                    })
+                    .await
+                }
+            })
+            .await
+    }
+}
+
+// ===== Client =====
+/// Connect side for a `Client`, runs the Noise handshake as initiator on
+/// every stream fetched from the wrapped provider. Only the server whose
+/// public key was given is accepted, unless any server is allowed with
+/// [`with_any_server`](Self::with_any_server). [`NoisePattern::IK`] needs
+/// the key either way.
+pub struct NoiseConnectProvider<P, S> {
+    inner: P,
+    keypair: NoiseKeypair,
+    pattern: NoisePattern,
+    server: Option<[u8; KEY_SIZE]>,
+    any_server: bool,
+    _stream: PhantomData<S>,
+}
+
+impl<P, S> NoiseConnectProvider<P, S> {
+    pub fn new(inner: P, keypair: NoiseKeypair) -> Self {
+        Self {
+            inner,
+            keypair,
+            pattern: NoisePattern::default(),
+            server: None,
+            any_server: false,
+            _stream: PhantomData,
+        }
+    }
+
+    pub fn with_pattern(mut self, pattern: NoisePattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    pub fn with_server_key(mut self, public: [u8; KEY_SIZE]) -> Self {
+        self.server = Some(public);
+        self
+    }
+
+    /// Connects to a server without its key, whatever key it presents, e.g.
+    /// when the server is authenticated by other means. Not possible with
+    /// [`NoisePattern::IK`].
+    pub fn with_any_server(mut self) -> Self {
+        self.any_server = true;
+        self
+    }
+
+    fn initiator(&self) -> Result<HandshakeState> {
+        let mut builder = Builder::new(self.pattern.params())
+            .local_private_key(&self.keypair.private)
+            .and_then(|builder| builder.prologue(PROLOGUE))
+            .map_err(into_io_error)?;
+
+        let ik = self.pattern == NoisePattern::IK;
+        match &self.server {
+            Some(server) if ik => {
+                builder = builder.remote_public_key(server).map_err(into_io_error)?;
+            }
+            None if ik || !self.any_server => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "server key must be provided",
+                ));
+            }
+            _ => {}
+        }
+
+        builder.build_initiator().map_err(into_io_error)
+    }
+}
+
+impl<P, S> Provider<NoiseStream<S>> for NoiseConnectProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<NoiseStream<S>> {
+        let state = self.initiator().ok()?;
+        let stream = self.inner.fetch().await?;
+
+        let (server, any_server) = (self.server, self.any_server);
+        handshake(stream, state, move |remote| match server {
+            Some(server) => server[..] == *remote,
+            None => any_server,
+        })
+        .await
+        .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    struct Streams(Option<DuplexStream>);
+
+    impl Provider<DuplexStream> for Streams {
+        async fn fetch(&mut self) -> Option<DuplexStream> {
+            self.0.take()
+        }
+    }
+
+    type Accept = NoiseAcceptProvider<Streams, DuplexStream>;
+    type Connect = NoiseConnectProvider<Streams, DuplexStream>;
+
+    /// Runs one handshake, returning the streams of the server and the
+    /// client if they completed it.
+    async fn connect(
+        server: impl FnOnce(Accept) -> Accept,
+        client: impl FnOnce(Connect, &NoiseKeypair) -> Connect,
+        keypair: &NoiseKeypair,
+    ) -> (
+        Option<NoiseStream<DuplexStream>>,
+        Option<NoiseStream<DuplexStream>>,
+    ) {
+        let (server_stream, client_stream) = duplex(MAX_MESSAGE_SIZE);
+        let server_keypair = NoiseKeypair::generate().unwrap();
+
+        let mut connect = client(
+            NoiseConnectProvider::new(Streams(Some(client_stream)), keypair.clone()),
+            &server_keypair,
+        );
+        let mut accept = server(NoiseAcceptProvider::new(
+            Streams(Some(server_stream)),
+            server_keypair,
+        ));
+
+        tokio::join!(accept.fetch(), connect.fetch())
+    }
+
+    /// Runs one handshake with a client knowing the server's key, returning
+    /// whether the server and the client completed it.
+    async fn handshake(
+        server: impl FnOnce(Accept) -> Accept,
+        client: &NoiseKeypair,
+    ) -> (bool, bool) {
+        let pinned =
+            |connect: Connect, server: &NoiseKeypair| connect.with_server_key(*server.public());
+        let (accepted, connected) = connect(server, pinned, client).await;
+        (accepted.is_some(), connected.is_some())
+    }
+
+    #[tokio::test]
+    async fn clients_are_rejected_unless_listed() {
+        let client = NoiseKeypair::generate().unwrap();
+        let other = NoiseKeypair::generate().unwrap();
+
+        let (accepted, _) = handshake(|server| server, &client).await;
+        assert!(!accepted);
+
+        let (accepted, _) = handshake(|server| server.with_client(*other.public()), &client).await;
+        assert!(!accepted);
+
+        let (accepted, connected) =
+            handshake(|server| server.with_client(*client.public()), &client).await;
+        assert!(accepted && connected);
+    }
+
+    #[tokio::test]
+    async fn any_client_is_accepted_when_allowed() {
+        let client = NoiseKeypair::generate().unwrap();
+
+        let (accepted, connected) = handshake(|server| server.with_any_client(), &client).await;
+        assert!(accepted && connected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn servers_are_rejected_unless_known_or_any_allowed() {
+        let client = NoiseKeypair::generate().unwrap();
+        let other = NoiseKeypair::generate().unwrap();
+        let server = |accept: Accept| accept.with_any_client();
+
+        let (_, connected) = connect(server, |connect, _| connect, &client).await;
+        assert!(connected.is_none());
+
+        let wrong = |connect: Connect, _: &NoiseKeypair| connect.with_server_key(*other.public());
+        let (_, connected) = connect(server, wrong, &client).await;
+        assert!(connected.is_none());
+
+        let any = |connect: Connect, _: &NoiseKeypair| connect.with_any_server();
+        let (_, connected) = connect(server, any, &client).await;
+        assert!(connected.is_some());
+
+        let ik_any = |connect: Connect, _: &NoiseKeypair| {
+            connect.with_pattern(NoisePattern::IK).with_any_server()
+        };
+        let (_, connected) = connect(server, ik_any, &client).await;
+        assert!(connected.is_none());
+    }
+
+    #[tokio::test]
+    async fn data_crosses_the_rekey_interval_both_ways() {
+        const MESSAGES: u32 = REKEY_INTERVAL as u32 + 16;
+
+        let client = NoiseKeypair::generate().unwrap();
+        let pinned =
+            |connect: Connect, server: &NoiseKeypair| connect.with_server_key(*server.public());
+        let (server, client) = connect(|server| server.with_any_client(), pinned, &client).await;
+
+        let (mut server_reader, mut server_writer) = tokio::io::split(server.unwrap());
+        let (mut client_reader, mut client_writer) = tokio::io::split(client.unwrap());
+
+        // One message per write, numbered so a message opened with the wrong
+        // key or out of order shows.
+        async fn send(writer: &mut (impl AsyncWrite + Unpin)) {
+            for i in 0..MESSAGES {
+                writer.write_all(&i.to_be_bytes()).await.unwrap();
+            }
+        }
+
+        async fn receive(reader: &mut (impl AsyncRead + Unpin)) {
+            for i in 0..MESSAGES {
+                assert_eq!(reader.read_u32().await.unwrap(), i);
+            }
+        }
+
+        tokio::join!(
+            send(&mut client_writer),
+            send(&mut server_writer),
+            receive(&mut server_reader),
+            receive(&mut client_reader),
+        );
+
+        let client = client_reader.unsplit(client_writer);
+        let transport = &client.inner.codec().transport;
+        assert_eq!(transport.sending_nonce(), MESSAGES as u64);
+        assert_eq!(transport.receiving_nonce(), MESSAGES as u64);
+    }
+}
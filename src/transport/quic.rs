@@ -0,0 +1,292 @@
+use std::io::{Error, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use quinn::rustls::RootCertStore;
+use quinn::{
+    ClientConfig, Connection, ConnectionError, Endpoint, Incoming, RecvStream, SendStream,
+    ServerConfig,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::JoinSet;
+
+use super::handshake::Handshakes;
+use crate::Provider;
+
+/// A bidirectional QUIC stream, one per tunnel.
+#[derive(Debug)]
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self { send, recv }
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.send)
+            .poll_write(cx, buf)
+            .map_err(Error::from)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+// ===== Endpoints =====
+/// An endpoint accepting connections on `address` with the certificate
+/// chain, leaf first, and its private key.
+pub fn server_endpoint(
+    address: SocketAddr,
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Endpoint> {
+    let config = ServerConfig::with_single_cert(certificates, key).map_err(Error::other)?;
+    Endpoint::server(config, address)
+}
+
+/// An endpoint connecting from `address` to servers whose certificate chains
+/// up to one of `roots`, e.g. a self-signed server certificate itself.
+pub fn client_endpoint(
+    address: SocketAddr,
+    roots: Vec<CertificateDer<'static>>,
+) -> Result<Endpoint> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root).map_err(Error::other)?;
+    }
+
+    let config = ClientConfig::with_root_certificates(Arc::new(store)).map_err(Error::other)?;
+    let mut endpoint = Endpoint::client(address)?;
+    endpoint.set_default_client_config(config);
+
+    Ok(endpoint)
+}
+
+// ===== Server =====
+type Accepted = (
+    Connection,
+    std::result::Result<(SendStream, RecvStream), ConnectionError>,
+);
+
+/// Connections arriving at an endpoint, before their handshake.
+struct IncomingProvider(Endpoint);
+
+impl Provider<Incoming> for IncomingProvider {
+    async fn fetch(&mut self) -> Option<Incoming> {
+        self.0.accept().await
+    }
+}
+
+/// Accept side for a `Server`, yields every bidirectional stream of every
+/// connection accepted on the endpoint.
+pub struct QuicAcceptProvider {
+    incoming: IncomingProvider,
+    handshakes: Handshakes<Connection>,
+    streams: JoinSet<Accepted>,
+}
+
+impl QuicAcceptProvider {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            incoming: IncomingProvider(endpoint),
+            handshakes: Handshakes::new(),
+            streams: JoinSet::new(),
+        }
+    }
+
+    fn accept_bi(&mut self, connection: Connection) {
+        self.streams.spawn(async move {
+            let accepted = connection.accept_bi().await;
+            (connection, accepted)
+        });
+    }
+}
+
+impl Provider<QuicStream> for QuicAcceptProvider {
+    async fn fetch(&mut self) -> Option<QuicStream> {
+        loop {
+            if self.handshakes.is_done() && self.streams.is_empty() {
+                return None;
+            }
+
+            tokio::select! {
+                connection = self.handshakes.next(&mut self.incoming, |incoming| async move {
+                    incoming.await.map_err(Error::from)
+                }), if !self.handshakes.is_done() => {
+                    if let Some(connection) = connection {
+                        self.accept_bi(connection);
+                    }
+                }
+
+                Some(joined) = self.streams.join_next(), if !self.streams.is_empty() => {
+                    if let Ok((connection, Ok((send, recv)))) = joined {
+                        self.accept_bi(connection);
+                        return Some(QuicStream::new(send, recv));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// ===== Client =====
+/// Connect side for a `Client`, opens a new bidirectional stream per fetch
+/// on one shared connection, which is re-established once it is lost.
+pub struct QuicConnectProvider {
+    endpoint: Endpoint,
+    address: SocketAddr,
+    server_name: String,
+    connection: Option<Connection>,
+}
+
+impl QuicConnectProvider {
+    pub fn new<S: Into<String>>(endpoint: Endpoint, address: SocketAddr, server_name: S) -> Self {
+        Self {
+            endpoint,
+            address,
+            server_name: server_name.into(),
+            connection: None,
+        }
+    }
+
+    async fn connection(&mut self) -> Result<Connection> {
+        if let Some(connection) = &self.connection {
+            if connection.close_reason().is_none() {
+                return Ok(connection.clone());
+            }
+        }
+
+        let connection = self
+            .endpoint
+            .connect(self.address, &self.server_name)
+            .map_err(Error::other)?
+            .await?;
+
+        self.connection = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+impl Provider<QuicStream> for QuicConnectProvider {
+    async fn fetch(&mut self) -> Option<QuicStream> {
+        // A connection can die between the liveness check and opening the
+        // stream, retry once on a fresh one.
+        for _ in 0..2 {
+            let connection = self.connection().await.ok()?;
+
+            match connection.open_bi().await {
+                Ok((send, recv)) => return Some(QuicStream::new(send, recv)),
+                Err(_) => self.connection = None,
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const SERVER_NAME: &str = "localhost";
+
+    /// A server echoing every stream, and a provider connecting to it.
+    fn connect() -> QuicConnectProvider {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).unwrap();
+        let certificate = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+
+        let loopback = SocketAddr::from(([127, 0, 0, 1], 0));
+        let server = server_endpoint(loopback, vec![certificate.clone()], key).unwrap();
+        let address = server.local_addr().unwrap();
+
+        let mut accept = QuicAcceptProvider::new(server);
+        tokio::spawn(async move {
+            while let Some(stream) = accept.fetch().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = tokio::io::split(stream);
+                    tokio::io::copy(&mut reader, &mut writer).await?;
+                    writer.shutdown().await
+                });
+            }
+        });
+
+        let client = client_endpoint(loopback, vec![certificate]).unwrap();
+        QuicConnectProvider::new(client, address, SERVER_NAME)
+    }
+
+    async fn echo(stream: &mut QuicStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        echoed
+    }
+
+    fn connection_id(provider: &QuicConnectProvider) -> usize {
+        provider.connection.as_ref().unwrap().stable_id()
+    }
+
+    #[tokio::test]
+    async fn streams_share_one_connection() {
+        let mut provider = connect();
+
+        let mut first = provider.fetch().await.unwrap();
+        let connection = connection_id(&provider);
+        let mut second = provider.fetch().await.unwrap();
+        assert_eq!(connection_id(&provider), connection);
+
+        // Either stream is served on its own, whatever the order.
+        assert_eq!(echo(&mut second, b"second").await, b"second");
+        assert_eq!(echo(&mut first, b"first").await, b"first");
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_connection_closes() {
+        let mut provider = connect();
+
+        let mut stream = provider.fetch().await.unwrap();
+        assert_eq!(echo(&mut stream, b"before").await, b"before");
+        let connection = connection_id(&provider);
+
+        provider
+            .connection
+            .as_ref()
+            .unwrap()
+            .close(0u32.into(), b"closed by test");
+
+        let mut stream = provider.fetch().await.unwrap();
+        assert_ne!(connection_id(&provider), connection);
+        assert_eq!(echo(&mut stream, b"after").await, b"after");
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socket2::SockRef;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use super::{backoff_accept, SocketOptions};
+use crate::Provider;
+
+/// Accept side for a `Server`, yields every connection accepted on the
+/// listener.
+pub struct TcpAcceptProvider {
+    listener: TcpListener,
+    options: SocketOptions,
+}
+
+impl TcpAcceptProvider {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            options: SocketOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl Provider<TcpStream> for TcpAcceptProvider {
+    async fn fetch(&mut self) -> Option<TcpStream> {
+        loop {
+            let (stream, _) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    backoff_accept(&error).await;
+                    continue;
+                }
+            };
+
+            if self.options.apply_tcp(SockRef::from(&stream)).is_ok() {
+                return Some(stream);
+            }
+        }
+    }
+}
+
+/// Connect side for a `Client`, opens a new connection to the server per
+/// fetch.
+pub struct TcpConnectProvider {
+    address: SocketAddr,
+    options: SocketOptions,
+}
+
+impl TcpConnectProvider {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            options: SocketOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl Provider<TcpStream> for TcpConnectProvider {
+    async fn fetch(&mut self) -> Option<TcpStream> {
+        let stream = TcpStream::connect(self.address).await.ok()?;
+        self.options.apply_tcp(SockRef::from(&stream)).ok()?;
+
+        Some(stream)
+    }
+}
+
+// ===== Happy Eyeballs =====
+/// Connects to the first of `addresses` to answer, racing them as in
+/// [RFC 8305]. Families are interleaved starting with the first address's,
+/// and the next attempt starts once the previous one failed or `delay`
+/// passed without an answer. Fails with the last error if all attempts do.
+///
+/// [RFC 8305]: https://www.rfc-editor.org/rfc/rfc8305
+pub async fn connect_happy_eyeballs(
+    addresses: Vec<SocketAddr>,
+    delay: Duration,
+) -> Result<TcpStream> {
+    let mut addresses = interleave(addresses).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(address) = addresses.next() {
+            attempts.spawn(TcpStream::connect(address));
+        }
+
+        if attempts.is_empty() {
+            return Err(last_error
+                .unwrap_or_else(|| Error::new(ErrorKind::NotFound, "no address to connect to")));
+        }
+
+        let remaining = addresses.len() > 0;
+
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(error)) => last_error = Some(error),
+                Err(error) => last_error = Some(Error::other(error)),
+            },
+
+            _ = tokio::time::sleep(delay), if remaining => {}
+        }
+    }
+}
+
+/// Alternates address families, keeping the order within each.
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addresses.first() else {
+        return addresses;
+    };
+
+    let first_ipv6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_ipv6);
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    const DELAY: Duration = Duration::from_secs(10);
+
+    /// An address nothing listens on.
+    async fn refused() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn interleave_alternates_families_from_the_first() {
+        let v4 = |port| SocketAddr::from(([192, 0, 2, 1], port));
+        let v6 = |port| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], port));
+
+        assert_eq!(
+            interleave(vec![v6(1), v6(2), v4(3), v4(4), v6(5)]),
+            vec![v6(1), v4(3), v6(2), v4(4), v6(5)]
+        );
+        assert_eq!(
+            interleave(vec![v4(1), v6(2), v6(3)]),
+            vec![v4(1), v6(2), v6(3)]
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn refused_addresses_fall_through_without_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+
+        let started = Instant::now();
+        let stream = connect_happy_eyeballs(vec![refused().await, live], DELAY)
+            .await
+            .unwrap();
+
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        assert!(started.elapsed() < DELAY);
+    }
+
+    #[tokio::test]
+    async fn failing_attempts_return_the_last_error() {
+        // Link-local without a scope fails right away, but is not refused.
+        let unscoped = SocketAddr::from(([0xfe80, 0, 0, 0, 0, 0, 0, 1], 80));
+
+        let error = connect_happy_eyeballs(vec![refused().await, unscoped], DELAY)
+            .await
+            .unwrap_err();
+        assert_ne!(error.kind(), ErrorKind::ConnectionRefused);
+
+        let error = connect_happy_eyeballs(vec![unscoped, refused().await], DELAY)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+
+        let error = connect_happy_eyeballs(Vec::new(), DELAY).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+}
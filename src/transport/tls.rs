@@ -0,0 +1,440 @@
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::ParsedCertificate;
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use super::handshake::Handshakes;
+use crate::Provider;
+
+// ===== Server =====
+pub struct TlsServerConfigBuilder {
+    certificates: Vec<CertificateDer<'static>>,
+    key: Option<PrivateKeyDer<'static>>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl Default for TlsServerConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsServerConfigBuilder {
+    pub fn new() -> Self {
+        Self {
+            certificates: Vec::new(),
+            key: None,
+            alpn: Vec::new(),
+        }
+    }
+
+    /// Certificate chain, leaf first, and its private key.
+    pub fn with_certificate(
+        mut self,
+        certificates: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.certificates = certificates;
+        self.key = Some(key);
+        self
+    }
+
+    /// Loads the certificate chain and private key from PEM files.
+    pub fn with_pem_files<C, K>(self, certificates: C, key: K) -> Result<Self>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let certificates = CertificateDer::pem_file_iter(certificates)
+            .map_err(Error::other)?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(Error::other)?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(Error::other)?;
+
+        Ok(self.with_certificate(certificates, key))
+    }
+
+    /// Protocol offered through ALPN, may be called repeatedly in order of
+    /// preference.
+    pub fn with_alpn<A: Into<Vec<u8>>>(mut self, protocol: A) -> Self {
+        self.alpn.push(protocol.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<ServerConfig>> {
+        let key = self
+            .key
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "certificate must be provided"))?;
+
+        let mut config = ServerConfig::builder_with_provider(ring::default_provider().into())
+            .with_safe_default_protocol_versions()
+            .map_err(Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(self.certificates, key)
+            .map_err(Error::other)?;
+        config.alpn_protocols = self.alpn;
+
+        Ok(config.into())
+    }
+}
+
+/// Accept side for a `Server`, runs the TLS handshake on every stream
+/// yielded by the wrapped provider.
+pub struct TlsAcceptProvider<P, S> {
+    inner: P,
+    acceptor: TlsAcceptor,
+    handshakes: Handshakes<server::TlsStream<S>>,
+}
+
+impl<P, S> TlsAcceptProvider<P, S>
+where
+    S: Send + 'static,
+{
+    pub fn new(inner: P, config: Arc<ServerConfig>) -> Self {
+        Self {
+            inner,
+            acceptor: config.into(),
+            handshakes: Handshakes::new(),
+        }
+    }
+}
+
+impl<P, S> Provider<server::TlsStream<S>> for TlsAcceptProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<server::TlsStream<S>> {
+        let acceptor = &self.acceptor;
+
+        self.handshakes
+            .next(&mut self.inner, |stream| acceptor.accept(stream))
+            .await
+    }
+}
+
+// ===== Client =====
+pub struct TlsClientConfigBuilder {
+    roots: RootCertStore,
+    pins: Vec<[u8; 32]>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl Default for TlsClientConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsClientConfigBuilder {
+    pub fn new() -> Self {
+        Self {
+            roots: RootCertStore::empty(),
+            pins: Vec::new(),
+            alpn: Vec::new(),
+        }
+    }
+
+    pub fn with_root_certificate(mut self, certificate: CertificateDer<'static>) -> Result<Self> {
+        self.roots.add(certificate).map_err(Error::other)?;
+        Ok(self)
+    }
+
+    /// Pins the SHA-256 of the server's SubjectPublicKeyInfo, see
+    /// [`spki_sha256`], may be called repeatedly. With pins and no root
+    /// certificates, any certificate with a pinned key is accepted, which
+    /// suits self-signed certificates.
+    pub fn with_pin(mut self, spki_sha256: [u8; 32]) -> Self {
+        self.pins.push(spki_sha256);
+        self
+    }
+
+    /// Protocol offered through ALPN, may be called repeatedly in order of
+    /// preference.
+    pub fn with_alpn<A: Into<Vec<u8>>>(mut self, protocol: A) -> Self {
+        self.alpn.push(protocol.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<ClientConfig>> {
+        let provider = Arc::new(ring::default_provider());
+
+        let roots = match self.roots.is_empty() {
+            true => None,
+            false => Some(
+                WebPkiServerVerifier::builder_with_provider(self.roots.into(), provider.clone())
+                    .build()
+                    .map_err(Error::other)?,
+            ),
+        };
+
+        let verifier: Arc<dyn ServerCertVerifier> = match (roots, self.pins.is_empty()) {
+            (Some(roots), true) => roots,
+            (roots, false) => Arc::new(PinnedVerifier {
+                pins: self.pins,
+                roots,
+                provider: provider.clone(),
+            }),
+            (None, true) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "root certificate or pin must be provided",
+                ))
+            }
+        };
+
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+        config.alpn_protocols = self.alpn;
+
+        Ok(config.into())
+    }
+}
+
+/// Connect side for a `Client`, runs the TLS handshake on every stream
+/// fetched from the wrapped provider. `server_name` is sent as SNI,
+/// independent of the address connected to, and verified against the
+/// certificate unless the configuration relies on pins alone.
+pub struct TlsConnectProvider<P, S> {
+    inner: P,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    _stream: PhantomData<S>,
+}
+
+impl<P, S> TlsConnectProvider<P, S> {
+    pub fn new(inner: P, config: Arc<ClientConfig>, server_name: &str) -> Result<Self> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?
+            .to_owned();
+
+        Ok(Self {
+            inner,
+            connector: config.into(),
+            server_name,
+            _stream: PhantomData,
+        })
+    }
+}
+
+impl<P, S> Provider<client::TlsStream<S>> for TlsConnectProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<client::TlsStream<S>> {
+        let stream = self.inner.fetch().await?;
+
+        self.connector
+            .connect(self.server_name.clone(), stream)
+            .await
+            .ok()
+    }
+}
+
+// ===== Pinning =====
+/// SHA-256 of the SubjectPublicKeyInfo of a DER certificate, the value
+/// expected by [`TlsClientConfigBuilder::with_pin`].
+pub fn spki_sha256(certificate: &CertificateDer<'_>) -> Result<[u8; 32]> {
+    let certificate = ParsedCertificate::try_from(certificate)
+        .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+    Ok(Sha256::digest(certificate.subject_public_key_info()).into())
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<[u8; 32]>,
+    roots: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let pinned = spki_sha256(end_entity).is_ok_and(|spki| self.pins.contains(&spki));
+        if !pinned {
+            return Err(rustls::Error::General(
+                "certificate public key is not pinned".to_string(),
+            ));
+        }
+
+        match &self.roots {
+            Some(roots) => {
+                roots.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            }
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            certificate,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            certificate,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::transport::tcp::{TcpAcceptProvider, TcpConnectProvider};
+
+    const SERVER_NAME: &str = "tunnel.example";
+    const ALPN: &[u8] = b"quicos";
+
+    struct Certified {
+        certificate: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+        spki: Vec<u8>,
+    }
+
+    fn certified() -> Certified {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).unwrap();
+
+        Certified {
+            certificate: certified.cert.der().clone(),
+            key: PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into()),
+            spki: certified.key_pair.public_key_der(),
+        }
+    }
+
+    /// A TLS server for `certified` and the address it listens on.
+    async fn listen(
+        certified: Certified,
+    ) -> (TlsAcceptProvider<TcpAcceptProvider, TcpStream>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let config = TlsServerConfigBuilder::new()
+            .with_certificate(vec![certified.certificate], certified.key)
+            .with_alpn(ALPN)
+            .build()
+            .unwrap();
+
+        (
+            TlsAcceptProvider::new(TcpAcceptProvider::new(listener), config),
+            address,
+        )
+    }
+
+    fn connect(
+        address: SocketAddr,
+        pin: [u8; 32],
+    ) -> TlsConnectProvider<TcpConnectProvider, TcpStream> {
+        let config = TlsClientConfigBuilder::new()
+            .with_pin(pin)
+            .with_alpn(ALPN)
+            .build()
+            .unwrap();
+
+        TlsConnectProvider::new(TcpConnectProvider::new(address), config, SERVER_NAME).unwrap()
+    }
+
+    #[test]
+    fn spki_sha256_hashes_the_public_key() {
+        let certified = certified();
+
+        let expected: [u8; 32] = Sha256::digest(&certified.spki).into();
+        assert_eq!(spki_sha256(&certified.certificate).unwrap(), expected);
+
+        let error = spki_sha256(&CertificateDer::from(vec![0x30, 0x00])).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn pinned_key_is_accepted_with_sni_and_alpn() {
+        let certified = certified();
+        let pin = spki_sha256(&certified.certificate).unwrap();
+        let (mut server, address) = listen(certified).await;
+        let mut client = connect(address, pin);
+
+        let (server, client) = tokio::join!(server.fetch(), client.fetch());
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+        let (_, connection) = server.get_ref();
+        assert_eq!(connection.server_name(), Some(SERVER_NAME));
+        assert_eq!(connection.alpn_protocol(), Some(ALPN));
+        assert_eq!(client.get_ref().1.alpn_protocol(), Some(ALPN));
+
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn wrong_pin_is_rejected() {
+        let certified = certified();
+        let mut pin = spki_sha256(&certified.certificate).unwrap();
+        pin[0] ^= 0xFF;
+        let (mut server, address) = listen(certified).await;
+        tokio::spawn(async move { server.fetch().await });
+
+        assert!(connect(address, pin).fetch().await.is_none());
+    }
+
+    #[test]
+    fn server_config_without_certificate_is_rejected() {
+        let error = TlsServerConfigBuilder::new().build().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
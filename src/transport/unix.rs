@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use socket2::SockRef;
+use tokio::net::{UnixListener, UnixStream};
+
+use super::{backoff_accept, SocketOptions};
+use crate::Provider;
+
+/// Accept side for a `Server`, yields every connection accepted on the Unix
+/// domain socket.
+pub struct UnixAcceptProvider {
+    listener: UnixListener,
+    options: SocketOptions,
+}
+
+impl UnixAcceptProvider {
+    pub fn new(listener: UnixListener) -> Self {
+        Self {
+            listener,
+            options: SocketOptions::default(),
+        }
+    }
+
+    /// Only the buffer sizes apply to Unix domain sockets.
+    pub fn with_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl Provider<UnixStream> for UnixAcceptProvider {
+    async fn fetch(&mut self) -> Option<UnixStream> {
+        loop {
+            let (stream, _) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    backoff_accept(&error).await;
+                    continue;
+                }
+            };
+
+            if self.options.apply(SockRef::from(&stream)).is_ok() {
+                return Some(stream);
+            }
+        }
+    }
+}
+
+/// Connect side for a `Client`, opens a new connection to the Unix domain
+/// socket per fetch.
+pub struct UnixConnectProvider {
+    path: PathBuf,
+    options: SocketOptions,
+}
+
+impl UnixConnectProvider {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            options: SocketOptions::default(),
+        }
+    }
+
+    /// Only the buffer sizes apply to Unix domain sockets.
+    pub fn with_options(mut self, options: SocketOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+impl Provider<UnixStream> for UnixConnectProvider {
+    async fn fetch(&mut self) -> Option<UnixStream> {
+        let stream = UnixStream::connect(&self.path).await.ok()?;
+        self.options.apply(SockRef::from(&stream)).ok()?;
+
+        Some(stream)
+    }
+}
@@ -0,0 +1,388 @@
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::machine::TryParse;
+use tokio_tungstenite::tungstenite::handshake::server::{create_response, write_response, Request};
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use super::handshake::Handshakes;
+use crate::Provider;
+
+const DEFAULT_PATH: &str = "/";
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// A WebSocket connection as a byte stream, each write is sent as a binary
+/// message and binary messages are read back as bytes. Other messages are
+/// skipped, a close message ends the stream.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    buffer: Bytes,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            buffer: Bytes::new(),
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        while !self.buffer.has_remaining() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.buffer = data,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+            }
+        }
+
+        let len = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer[..len]);
+        self.buffer.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+
+        let message = Message::Binary(Bytes::copy_from_slice(buf));
+        Pin::new(&mut self.inner)
+            .start_send(message)
+            .map_err(into_io_error)?;
+
+        // Messages are buffered until flushed, send them right away like a
+        // socket would. A pending flush is driven by the next write or flush.
+        if let Poll::Ready(Err(error)) = Pin::new(&mut self.inner).poll_flush(cx) {
+            return Poll::Ready(Err(into_io_error(error)));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match ready!(Pin::new(&mut self.inner).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(error) => Poll::Ready(Err(into_io_error(error))),
+        }
+    }
+}
+
+fn into_io_error(error: WsError) -> Error {
+    match error {
+        WsError::Io(error) => error,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => {
+            Error::new(ErrorKind::BrokenPipe, error)
+        }
+        error => Error::other(error),
+    }
+}
+
+/// Accept side for a `Server`, upgrades every stream yielded by the wrapped
+/// provider to a WebSocket, e.g. behind an HTTP reverse proxy. Upgrades to
+/// other paths than the configured one are answered with `404`, requests
+/// that are no upgrade at all with `400`.
+pub struct WsAcceptProvider<P, S> {
+    inner: P,
+    path: String,
+    handshakes: Handshakes<WsStream<S>>,
+}
+
+impl<P, S> WsAcceptProvider<P, S>
+where
+    S: Send + 'static,
+{
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            path: DEFAULT_PATH.to_string(),
+            handshakes: Handshakes::new(),
+        }
+    }
+
+    pub fn with_path<T: Into<String>>(mut self, path: T) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl<P, S> Provider<WsStream<S>> for WsAcceptProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<WsStream<S>> {
+        let path = &self.path;
+
+        self.handshakes
+            .next(&mut self.inner, |stream| accept(stream, path.clone()))
+            .await
+    }
+}
+
+/// Answers the upgrade request read from `stream`. Unlike tungstenite's own
+/// accept, which drops requests it cannot upgrade unanswered, every refusal
+/// gets a status.
+async fn accept<S>(mut stream: S, path: String) -> Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let response = read_request(&mut stream).await?.and_then(|request| {
+        let response = create_response(&request).ok()?;
+        Some((request.uri().path() == path, response))
+    });
+
+    let response = match response {
+        Some((true, response)) => response,
+        refused => {
+            let status = match refused {
+                Some(_) => StatusCode::NOT_FOUND,
+                None => StatusCode::BAD_REQUEST,
+            };
+
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            stream.write_all(head.as_bytes()).await?;
+            stream.flush().await?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("websocket upgrade refused with {}", status),
+            ));
+        }
+    };
+
+    let mut head = Vec::new();
+    write_response(&mut head, &response).map_err(into_io_error)?;
+    stream.write_all(&head).await?;
+    stream.flush().await?;
+
+    let stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+    Ok(WsStream::new(stream))
+}
+
+/// Reads the request head, `None` if it is malformed or followed by data
+/// sent before the upgrade was answered.
+async fn read_request<S>(stream: &mut S) -> Result<Option<Request>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = BytesMut::with_capacity(1024);
+
+    loop {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "websocket upgrade request incomplete",
+            ));
+        }
+
+        match Request::try_parse(&buffer) {
+            Ok(Some((len, request))) => return Ok(Some(request).filter(|_| len == buffer.len())),
+            Ok(None) if buffer.len() < MAX_HEAD_SIZE => continue,
+            Ok(None) | Err(_) => return Ok(None),
+        }
+    }
+}
+
+/// Connect side for a `Client`, upgrades every stream fetched from the
+/// wrapped provider to a WebSocket with a `GET` to `host` and the configured
+/// path and headers.
+pub struct WsConnectProvider<P, S> {
+    inner: P,
+    host: String,
+    path: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    _stream: PhantomData<S>,
+}
+
+impl<P, S> WsConnectProvider<P, S> {
+    pub fn new<H: Into<String>>(inner: P, host: H) -> Self {
+        Self {
+            inner,
+            host: host.into(),
+            path: DEFAULT_PATH.to_string(),
+            headers: Vec::new(),
+            _stream: PhantomData,
+        }
+    }
+
+    pub fn with_path<T: Into<String>>(mut self, path: T) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Adds a header to the upgrade request, may be called repeatedly.
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self> {
+        let name = HeaderName::try_from(name)
+            .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+        let value = HeaderValue::try_from(value)
+            .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+
+        self.headers.push((name, value));
+        Ok(self)
+    }
+}
+
+impl<P, S> Provider<WsStream<S>> for WsConnectProvider<P, S>
+where
+    P: Provider<S> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn fetch(&mut self) -> Option<WsStream<S>> {
+        let stream = self.inner.fetch().await?;
+
+        let mut request = format!("ws://{}{}", self.host, self.path)
+            .into_client_request()
+            .ok()?;
+        for (name, value) in &self.headers {
+            request.headers_mut().append(name.clone(), value.clone());
+        }
+
+        let (stream, _) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .ok()?;
+
+        Some(WsStream::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::transport::tcp::{TcpAcceptProvider, TcpConnectProvider};
+
+    const PATH: &str = "/tunnel";
+
+    async fn listen() -> (WsAcceptProvider<TcpAcceptProvider, TcpStream>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let provider = WsAcceptProvider::new(TcpAcceptProvider::new(listener)).with_path(PATH);
+        (provider, address)
+    }
+
+    fn connect(
+        address: SocketAddr,
+        path: &str,
+    ) -> WsConnectProvider<TcpConnectProvider, TcpStream> {
+        WsConnectProvider::new(TcpConnectProvider::new(address), "tunnel.example").with_path(path)
+    }
+
+    /// Sends a raw request head and returns the status line answered.
+    async fn status_line(address: SocketAddr, head: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn upgrade_and_round_trip() {
+        let (mut server, address) = listen().await;
+        let mut client = connect(address, PATH);
+
+        let (server, client) = tokio::join!(server.fetch(), client.fetch());
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+
+        client.shutdown().await.unwrap();
+        assert_eq!(server.read(&mut received).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn payload_larger_than_a_message_is_read_whole() {
+        let (mut server, address) = listen().await;
+        let mut client = connect(address, PATH);
+
+        let (server, client) = tokio::join!(server.fetch(), client.fetch());
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+
+        let payload: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut received = vec![0u8; payload.len() * 2];
+
+        let send = async {
+            client.write_all(&payload).await.unwrap();
+            client.write_all(&payload).await.unwrap();
+        };
+        let (_, read) = tokio::join!(send, server.read_exact(&mut received));
+        read.unwrap();
+
+        assert_eq!(received[..payload.len()], payload);
+        assert_eq!(received[payload.len()..], payload);
+    }
+
+    #[tokio::test]
+    async fn other_paths_are_not_found() {
+        let (mut server, address) = listen().await;
+        tokio::spawn(async move { server.fetch().await });
+
+        assert!(connect(address, "/other").fetch().await.is_none());
+
+        let head = "GET /other HTTP/1.1\r\nHost: tunnel.example\r\n\
+                    Connection: Upgrade\r\nUpgrade: websocket\r\n\
+                    Sec-WebSocket-Version: 13\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        assert_eq!(status_line(address, head).await, "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn requests_without_upgrade_are_bad() {
+        let (mut server, address) = listen().await;
+        tokio::spawn(async move { server.fetch().await });
+
+        let head = "GET /tunnel HTTP/1.1\r\nHost: tunnel.example\r\n\r\n";
+        assert_eq!(status_line(address, head).await, "HTTP/1.1 400 Bad Request");
+    }
+}